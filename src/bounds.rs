@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// An estimate together with the bounds it's expected to lie within, as returned by [`HyperLogLog::len_with_bounds`](crate::HyperLogLog::len_with_bounds) and [`CountMinSketch::get_with_bounds`](crate::CountMinSketch::get_with_bounds).
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Bounds {
+	/// The lower bound.
+	pub lower: f64,
+	/// The point estimate.
+	pub estimate: f64,
+	/// The upper bound.
+	pub upper: f64,
+}
+
+/// The number of standard deviations either side of the mean of a normal distribution that contain `confidence` of it.
+pub(crate) fn z_score(confidence: f64) -> Result<f64, Error> {
+	if !(0.0 < confidence && confidence < 1.0) {
+		return Err(Error::InvalidParameter(
+			"confidence must be between 0 and 1",
+		));
+	}
+	Ok(normal_quantile(0.5 + confidence / 2.0))
+}
+
+/// The inverse of the standard normal CDF, with a relative error below 1.15e-9.
+///
+/// See [*An algorithm for computing the inverse normal cumulative distribution function*](https://web.archive.org/web/20151030215612/http://home.online.no/~pjacklam/notes/invnorm/) by Peter Acklam.
+fn normal_quantile(p: f64) -> f64 {
+	const A: [f64; 6] = [
+		-3.969_683_028_665_376e1,
+		2.209_460_984_245_205e2,
+		-2.759_285_104_469_687e2,
+		1.383_577_518_672_69e2,
+		-3.066_479_806_614_716e1,
+		2.506_628_277_459_239,
+	];
+	const B: [f64; 5] = [
+		-5.447_609_879_822_406e1,
+		1.615_858_368_580_409e2,
+		-1.556_989_798_598_866e2,
+		6.680_131_188_771_972e1,
+		-1.328_068_155_288_572e1,
+	];
+	const C: [f64; 6] = [
+		-7.784_894_002_430_293e-3,
+		-3.223_964_580_411_365e-1,
+		-2.400_758_277_161_838,
+		-2.549_732_539_343_734,
+		4.374_664_141_464_968,
+		2.938_163_982_698_783,
+	];
+	const D: [f64; 4] = [
+		7.784_695_709_041_462e-3,
+		3.224_671_290_700_398e-1,
+		2.445_134_137_142_996,
+		3.754_408_661_907_416,
+	];
+	const P_LOW: f64 = 0.024_25;
+	assert!(0.0 < p && p < 1.0);
+	let tail = |q: f64| {
+		(((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+			/ ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+	};
+	if p < P_LOW {
+		tail((-2.0 * p.ln()).sqrt())
+	} else if p <= 1.0 - P_LOW {
+		let q = p - 0.5;
+		let r = q * q;
+		(((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+			/ (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+	} else {
+		-tail((-2.0 * (1.0 - p).ln()).sqrt())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::z_score;
+
+	#[test]
+	fn z_scores() {
+		for &(confidence, z) in &[
+			(0.5, 0.674_489_750_196_081_7),
+			(0.682_689_492_137_085_9, 1.0),
+			(0.9, 1.644_853_626_951_472_2),
+			(0.95, 1.959_963_984_540_054),
+			(0.99, 2.575_829_303_548_901),
+			(0.999_999, 4.891_638_475_698_96),
+		] {
+			let actual = z_score(confidence).unwrap();
+			assert!((actual - z).abs() < 1e-6, "{} {} {}", confidence, actual, z);
+		}
+		for &confidence in &[0.0, 1.0, -0.5, f64::NAN] {
+			assert!(z_score(confidence).is_err());
+		}
+	}
+}
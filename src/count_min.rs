@@ -22,31 +22,132 @@
 
 use serde::{Deserialize, Serialize};
 use std::{
-	borrow::Borrow, cmp::max, convert::TryFrom, fmt, hash::{Hash, Hasher}, marker::PhantomData, ops
+	borrow::Borrow, cmp::{self, max}, convert::TryFrom, fmt, hash::{BuildHasher, Hash}, iter, marker::PhantomData, mem::size_of, ops
 };
-use twox_hash::XxHash;
 
-use super::f64_to_usize;
-use crate::traits::{Intersect, IntersectPlusUnionIsPlus, New, UnionAssign};
+use super::{f64_to_usize, usize_to_f64};
+use crate::{
+	bounds::Bounds, error::Error, hash::{fmix64, XxHashBuilder}, traits::{Intersect, IntersectPlusUnionIsPlus, New, ToF64, UnionAssign}
+};
+
+mod aligned;
+use self::aligned::{row_stride, Aligned, CACHE_LINE};
 
 /// An implementation of a [count-min sketch](https://en.wikipedia.org/wiki/Count–min_sketch) data structure with *conservative updating* for increased accuracy.
 ///
 /// This data structure is also known as a [counting Bloom filter](https://en.wikipedia.org/wiki/Bloom_filter#Counting_filters).
 ///
 /// See [*An Improved Data Stream Summary: The Count-Min Sketch and its Applications*](http://dimacs.rutgers.edu/~graham/pubs/papers/cm-full.pdf) and [*New Directions in Traffic Measurement and Accounting*](http://pages.cs.wisc.edu/~suman/courses/740/papers/estan03tocs.pdf) for background on the count-min sketch with conservative updating.
+///
+/// Keys are hashed with `S`, which defaults to [`XxHashBuilder`] with a seed of `0`.
+///
+/// The counters are held in a single allocation aligned to a cache line, arranged according to the [`CountMinLayout`]. The serialized form holds them the same way, so it isn't compatible with that of version 0.3 and earlier.
 #[derive(Serialize, Deserialize)]
+#[serde(
+	bound(
+		serialize = "C: Serialize, <C as New>::Config: Serialize, S: Serialize",
+		deserialize = "C: Deserialize<'de>, <C as New>::Config: Deserialize<'de>, S: Deserialize<'de>"
+	),
+	try_from = "CountMinSketchUnchecked<K, C, S>"
+)]
+pub struct CountMinSketch<K: ?Sized, C: New, S = XxHashBuilder> {
+	counters: Aligned<C>,
+	offsets: Vec<usize>, // to avoid malloc/free each push
+	mask: usize,
+	k_num: usize,
+	layout: CountMinLayout,
+	config: <C as New>::Config,
+	total: Option<C>, // the sum of the values pushed, if tracked for get_with_bounds
+	hasher: S,
+	marker: PhantomData<fn(K)>,
+}
+
+/// A deserialized [`CountMinSketch`] that hasn't yet been checked to be valid.
+#[derive(Deserialize)]
 #[serde(bound(
-	serialize = "C: Serialize, <C as New>::Config: Serialize",
-	deserialize = "C: Deserialize<'de>, <C as New>::Config: Deserialize<'de>"
+	deserialize = "C: Deserialize<'de>, <C as New>::Config: Deserialize<'de>, S: Deserialize<'de>"
 ))]
-pub struct CountMinSketch<K: ?Sized, C: New> {
-	counters: Vec<Vec<C>>,
-	offsets: Vec<usize>, // to avoid malloc/free each push
+struct CountMinSketchUnchecked<K: ?Sized, C: New, S> {
+	counters: Vec<C>,
+	offsets: Vec<usize>,
 	mask: usize,
 	k_num: usize,
+	layout: CountMinLayout,
 	config: <C as New>::Config,
+	total: Option<C>,
+	hasher: S,
 	marker: PhantomData<fn(K)>,
 }
+impl<K: ?Sized, C: New, S> TryFrom<CountMinSketchUnchecked<K, C, S>> for CountMinSketch<K, C, S> {
+	type Error = Error;
+
+	fn try_from(cms: CountMinSketchUnchecked<K, C, S>) -> Result<Self, Error> {
+		let CountMinSketchUnchecked {
+			counters,
+			offsets,
+			mask,
+			k_num,
+			layout,
+			config,
+			total,
+			hasher,
+			..
+		} = cms;
+		let width = mask.wrapping_add(1);
+		if k_num == 0
+			|| offsets.len() != k_num
+			|| width < 2
+			|| width & mask != 0
+			|| layout.len::<C>(width, k_num) != Some(counters.len())
+			// so that the layout can be changed to `Rows`
+			|| CountMinLayout::Rows.len::<C>(width, k_num).is_none()
+		{
+			return Err(Error::Deserialization(
+				"CountMinSketch counters are malformed",
+			));
+		}
+		Ok(Self {
+			counters: Aligned::from_vec(counters),
+			offsets,
+			mask,
+			k_num,
+			layout,
+			config,
+			total,
+			hasher,
+			marker: PhantomData,
+		})
+	}
+}
+
+/// How the counters of a [`CountMinSketch`] are arranged in memory, set with [`set_layout`](CountMinSketch::set_layout).
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default, Debug, Serialize, Deserialize)]
+pub enum CountMinLayout {
+	/// One row after another, each padded to a whole number of cache lines and starting on one, so that rows don't share lines. Each of a key's counters is in a different row.
+	#[default]
+	Rows,
+	/// Blocks holding a few counters from every row, so that all of a key's counters lie within one or two cache lines, as in the blocked Bloom filters of *Cache-, Hash- and Space-Efficient Bloom Filters* by Putze, Sanders and Singler.
+	///
+	/// Each key hashes to a single block, so its counters are no longer independent and estimates are somewhat less accurate, in return for a cache miss or two per [`push`](CountMinSketch::push) rather than one per row.
+	Blocked,
+}
+impl CountMinLayout {
+	/// The number of counters of type `C` in `depth` rows of `width`, including padding, or `None` if that overflows.
+	fn len<C>(self, width: usize, depth: usize) -> Option<usize> {
+		match self {
+			CountMinLayout::Rows => row_stride::<C>(width).checked_mul(depth),
+			CountMinLayout::Blocked => width.checked_mul(depth),
+		}
+	}
+}
+
+/// The number of counters each row contributes to a block of the blocked layout: enough for `depth` rows to fill a cache line, but at least two so that keys in the same block can still be told apart.
+fn block_width<C>(width: usize, depth: usize) -> usize {
+	let lanes = CACHE_LINE / size_of::<C>().max(1);
+	let sub = (lanes / depth).max(2);
+	// the largest power of two that fits
+	(1 << (usize::BITS - 1 - sub.leading_zeros())).min(width)
+}
 
 impl<K: ?Sized, C> CountMinSketch<K, C>
 where
@@ -55,20 +156,87 @@ where
 {
 	/// Create an empty `CountMinSketch` data structure with the specified error tolerance.
 	pub fn new(probability: f64, tolerance: f64, config: C::Config) -> Self {
-		let width = Self::optimal_width(tolerance);
-		let k_num = Self::optimal_k_num(probability);
-		let counters: Vec<Vec<C>> = (0..k_num)
-			.map(|_| (0..width).map(|_| C::new(&config)).collect())
-			.collect();
-		let offsets = vec![0; k_num];
-		Self {
+		Self::with_hasher(probability, tolerance, config, XxHashBuilder::default())
+	}
+
+	/// Like [`new`](Self::new), but returns an error if `probability` or `tolerance` are invalid.
+	pub fn try_new(probability: f64, tolerance: f64, config: C::Config) -> Result<Self, Error> {
+		Self::try_with_hasher(probability, tolerance, config, XxHashBuilder::default())
+	}
+
+	/// Create an empty `CountMinSketch` data structure with `depth` rows of `width` counters.
+	pub fn with_dimensions(width: usize, depth: usize, config: C::Config) -> Self {
+		Self::with_dimensions_and_hasher(width, depth, config, XxHashBuilder::default())
+	}
+
+	/// Like [`with_dimensions`](Self::with_dimensions), but returns an error if `width` or `depth` are invalid.
+	pub fn try_with_dimensions(
+		width: usize, depth: usize, config: C::Config,
+	) -> Result<Self, Error> {
+		Self::try_with_dimensions_and_hasher(width, depth, config, XxHashBuilder::default())
+	}
+}
+
+impl<K: ?Sized, C, S> CountMinSketch<K, C, S>
+where
+	K: Hash,
+	C: New + for<'a> UnionAssign<&'a C> + Intersect,
+	S: BuildHasher,
+{
+	/// Create an empty `CountMinSketch` data structure with the specified error tolerance, that hashes keys with `hasher`.
+	pub fn with_hasher(probability: f64, tolerance: f64, config: C::Config, hasher: S) -> Self {
+		Self::try_with_hasher(probability, tolerance, config, hasher)
+			.unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`with_hasher`](Self::with_hasher), but returns an error if `probability` or `tolerance` are invalid.
+	pub fn try_with_hasher(
+		probability: f64, tolerance: f64, config: C::Config, hasher: S,
+	) -> Result<Self, Error> {
+		let width = Self::optimal_width(tolerance)?;
+		let k_num = Self::optimal_k_num(probability)?;
+		Self::try_with_dimensions_and_hasher(width, k_num, config, hasher)
+	}
+
+	/// Create an empty `CountMinSketch` data structure with `depth` rows of `width` counters, that hashes keys with `hasher`.
+	///
+	/// With probability at least `1 - 2^-depth`, estimates exceed the true value by at most `2N/width`, where `N` is the sum of the values pushed. `width` must be a power of two, so that keys can be mapped to counters by masking their hashes.
+	pub fn with_dimensions_and_hasher(
+		width: usize, depth: usize, config: C::Config, hasher: S,
+	) -> Self {
+		Self::try_with_dimensions_and_hasher(width, depth, config, hasher)
+			.unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`with_dimensions_and_hasher`](Self::with_dimensions_and_hasher), but returns an error if `width` isn't a power of two of at least 2, or `depth` is 0.
+	pub fn try_with_dimensions_and_hasher(
+		width: usize, depth: usize, config: C::Config, hasher: S,
+	) -> Result<Self, Error> {
+		if width < 2 || !width.is_power_of_two() {
+			return Err(Error::InvalidParameter(
+				"width must be a power of two of at least 2",
+			));
+		}
+		if depth == 0 {
+			return Err(Error::InvalidParameter("depth must be at least 1"));
+		}
+		let layout = CountMinLayout::default();
+		let len = layout
+			.len::<C>(width, depth)
+			.ok_or(Error::InvalidParameter("width and depth are too large"))?;
+		let counters = Aligned::from_vec((0..len).map(|_| C::new(&config)).collect());
+		let offsets = vec![0; depth];
+		Ok(Self {
 			counters,
 			offsets,
 			mask: Self::mask(width),
-			k_num,
+			k_num: depth,
+			layout,
 			config,
+			total: None,
+			hasher,
 			marker: PhantomData,
-		}
+		})
 	}
 
 	/// "Visit" an element.
@@ -78,41 +246,39 @@ where
 		K: Borrow<Q>,
 		C: for<'a> ops::AddAssign<&'a V> + IntersectPlusUnionIsPlus,
 	{
+		self.push_hashed(self.hash(key), value)
+	}
+
+	/// "Visit" an element, given the hash of its key.
+	///
+	/// `hash` must be the result of hashing the key with the `CountMinSketch`'s hasher, which lets a hash already computed for something else, like a `HashMap` or a [`HyperLogLog`](crate::HyperLogLog) with the same hasher, be reused.
+	pub fn push_hashed<V: ?Sized>(&mut self, hash: u64, value: &V) -> C
+	where
+		C: for<'a> ops::AddAssign<&'a V> + IntersectPlusUnionIsPlus,
+	{
+		if let Some(total) = &mut self.total {
+			*total += value;
+		}
+		let offsets = self.offsets(hash);
+		self.offsets
+			.iter_mut()
+			.zip(offsets)
+			.for_each(|(offset, offset_new)| {
+				*offset = offset_new;
+			});
 		if !<C as IntersectPlusUnionIsPlus>::VAL {
-			let offsets = self.offsets(key);
-			self.offsets
-				.iter_mut()
-				.zip(offsets)
-				.for_each(|(offset, offset_new)| {
-					*offset = offset_new;
-				});
-			let mut lowest = C::intersect(
-				self.offsets
-					.iter()
-					.enumerate()
-					.map(|(k_i, &offset)| &self.counters[k_i][offset]),
-			)
-			.unwrap();
+			let mut lowest =
+				C::intersect(self.offsets.iter().map(|&offset| &self.counters[offset])).unwrap();
 			lowest += value;
-			self.counters
-				.iter_mut()
-				.zip(self.offsets.iter())
-				.for_each(|(counters, &offset)| {
-					counters[offset].union_assign(&lowest);
-				});
+			for &offset in &self.offsets {
+				self.counters[offset].union_assign(&lowest);
+			}
 			lowest
 		} else {
-			let offsets = self.offsets(key);
-			C::intersect(
-				self.counters
-					.iter_mut()
-					.zip(offsets)
-					.map(|(counters, offset)| {
-						counters[offset] += value;
-						&counters[offset]
-					}),
-			)
-			.unwrap()
+			for &offset in &self.offsets {
+				self.counters[offset] += value;
+			}
+			C::intersect(self.offsets.iter().map(|&offset| &self.counters[offset])).unwrap()
 		}
 	}
 
@@ -122,13 +288,9 @@ where
 		Q: Hash,
 		K: Borrow<Q>,
 	{
-		let offsets = self.offsets(key);
-		self.counters
-			.iter_mut()
-			.zip(offsets)
-			.for_each(|(counters, offset)| {
-				counters[offset].union_assign(value);
-			})
+		for offset in self.offsets(self.hash(key)) {
+			self.counters[offset].union_assign(value);
+		}
 	}
 
 	/// Retrieve an estimate of the aggregated value for `key`.
@@ -137,40 +299,191 @@ where
 		Q: Hash,
 		K: Borrow<Q>,
 	{
-		C::intersect(
-			self.counters
-				.iter()
-				.zip(self.offsets(key))
-				.map(|(counters, offset)| &counters[offset]),
-		)
-		.unwrap()
-	}
-
-	// pub fn estimate_memory(
-	// 	probability: f64, tolerance: f64,
-	// ) -> Result<usize, &'static str> {
-	// 	let width = Self::optimal_width(tolerance);
-	// 	let k_num = Self::optimal_k_num(probability);
-	// 	Ok(width * mem::size_of::<C>() * k_num)
-	// }
+		self.get_hashed(self.hash(key))
+	}
+
+	/// Retrieve an estimate of the aggregated value for a key, given its hash as for [`push_hashed`](Self::push_hashed).
+	pub fn get_hashed(&self, hash: u64) -> C {
+		C::intersect(self.offsets(hash).map(|offset| &self.counters[offset])).unwrap()
+	}
+
+	/// Retrieve an estimate of the aggregated value for `key`, together with bounds that it lies within with probability at least `1 - 2^-depth`.
+	///
+	/// Counters are only ever overestimated, so the upper bound is the estimate itself. If the sum of the values pushed is tracked, as enabled with [`set_track_total`](Self::set_track_total), the lower bound is the estimate less `εN`, where `ε = 2/`[`width`](Self::width) and `N` is that sum; otherwise it's `0`. Values merged with [`union_assign`](Self::union_assign) aren't included in `N`.
+	///
+	/// With the [`Blocked`](CountMinLayout::Blocked) layout a key's counters aren't independent, so the probability is somewhat lower.
+	pub fn get_with_bounds<Q: ?Sized>(&self, key: &Q) -> Bounds
+	where
+		Q: Hash,
+		K: Borrow<Q>,
+		C: ToF64,
+	{
+		let estimate = self.get(key).to_f64();
+		let epsilon = 2.0 / usize_to_f64(self.width());
+		let lower = self
+			.total
+			.as_ref()
+			.map_or(0.0, |total| (estimate - epsilon * total.to_f64()).max(0.0));
+		Bounds {
+			lower,
+			estimate,
+			upper: estimate,
+		}
+	}
+
+	/// Union another `CountMinSketch` into this one cell by cell, so that the aggregated value of each key is the union of its values in both.
+	///
+	/// Both must have been created with the same parameters and hasher, e.g. to sketch the partitions of a dataset separately.
+	pub fn union(&mut self, src: &Self)
+	where
+		C::Config: PartialEq,
+		S: PartialEq,
+	{
+		self.try_union(src).unwrap_or_else(|err| panic!("{}", err));
+	}
+
+	/// Like [`union`](Self::union), but returns an error if the width, depth, layout, counter config or hasher differ.
+	pub fn try_union(&mut self, src: &Self) -> Result<(), Error>
+	where
+		C::Config: PartialEq,
+		S: PartialEq,
+	{
+		self.check_compatible(src)?;
+		for (counter, src_counter) in self.counters.iter_mut().zip(src.counters.iter()) {
+			counter.union_assign(src_counter);
+		}
+		match self.total.as_mut().zip(src.total.as_ref()) {
+			Some((total, src_total)) => total.union_assign(src_total),
+			None => self.total = None,
+		}
+		Ok(())
+	}
+
+	/// Like [`AddAssign`](ops::AddAssign), adding another `CountMinSketch` to this one cell by cell, but returns an error if the width, depth, layout, counter config or hasher differ.
+	///
+	/// The aggregated value of each key becomes the sum of its values in both, as if everything pushed to `src` had also been pushed to `self`.
+	pub fn try_add(&mut self, src: &Self) -> Result<(), Error>
+	where
+		C: for<'a> ops::AddAssign<&'a C>,
+		C::Config: PartialEq,
+		S: PartialEq,
+	{
+		self.check_compatible(src)?;
+		for (counter, src_counter) in self.counters.iter_mut().zip(src.counters.iter()) {
+			*counter += src_counter;
+		}
+		match self.total.as_mut().zip(src.total.as_ref()) {
+			Some((total, src_total)) => *total += src_total,
+			None => self.total = None,
+		}
+		Ok(())
+	}
+
+	fn check_compatible(&self, src: &Self) -> Result<(), Error>
+	where
+		C::Config: PartialEq,
+		S: PartialEq,
+	{
+		if self.mask != src.mask || self.k_num != src.k_num {
+			return Err(Error::Incompatible(
+				"CountMinSketches must have the same width and depth to be merged",
+			));
+		}
+		if self.layout != src.layout {
+			return Err(Error::Incompatible(
+				"CountMinSketches must have the same layout to be merged",
+			));
+		}
+		if self.config != src.config {
+			return Err(Error::Incompatible(
+				"CountMinSketches must have the same counter config to be merged",
+			));
+		}
+		if self.hasher != src.hasher {
+			return Err(Error::Incompatible(
+				"CountMinSketches must have the same hasher to be merged",
+			));
+		}
+		Ok(())
+	}
+
+	/// The number of counters in each row.
+	pub fn width(&self) -> usize {
+		self.mask + 1
+	}
+
+	/// The number of rows, i.e. the number of counters each key maps to.
+	pub fn depth(&self) -> usize {
+		self.k_num
+	}
+
+	/// How the counters are arranged in memory.
+	pub fn layout(&self) -> CountMinLayout {
+		self.layout
+	}
+
+	/// Set how the counters are arranged in memory.
+	///
+	/// This clears the `CountMinSketch`, as counters can't be rearranged without the keys that map to them, so it should be called before anything is pushed.
+	pub fn set_layout(&mut self, layout: CountMinLayout) {
+		let config = &self.config;
+		// `Rows` is the longest layout, and it was checked not to overflow when `self` was created
+		let len = layout
+			.len::<C>(self.mask + 1, self.k_num)
+			.unwrap_or_else(|| unreachable!());
+		self.counters = Aligned::from_vec((0..len).map(|_| C::new(config)).collect());
+		self.total = self.total.as_ref().map(|_| C::new(config));
+		self.layout = layout;
+	}
+
+	/// Whether the sum of the values pushed is tracked, for the lower bound of [`get_with_bounds`](Self::get_with_bounds).
+	pub fn track_total(&self) -> bool {
+		self.total.is_some()
+	}
+
+	/// Set whether the sum of the values pushed is tracked, for the lower bound of [`get_with_bounds`](Self::get_with_bounds). It isn't by default.
+	///
+	/// The sum is held in a `C`, which must be wide enough for it as well as for each counter. Enabling it clears the `CountMinSketch`, as the sum of what was already pushed isn't known, so it should be called before anything is pushed. A sketch merged with one that doesn't track the sum stops tracking it.
+	pub fn set_track_total(&mut self, track: bool) {
+		if !track {
+			self.total = None;
+		} else if self.total.is_none() {
+			self.clear();
+			self.total = Some(C::new(&self.config));
+		}
+	}
+
+	/// The memory, in bytes, that a `CountMinSketch` created by [`new`](Self::new) with `probability` and `tolerance` would allocate for its counters.
+	///
+	/// This doesn't include any memory allocated by the counters themselves, such as the registers of a [`HyperLogLog`](crate::HyperLogLog).
+	pub fn estimate_memory(probability: f64, tolerance: f64) -> Result<usize, Error> {
+		let width = Self::optimal_width(tolerance)?;
+		let k_num = Self::optimal_k_num(probability)?;
+		Ok(k_num * (row_stride::<C>(width) * size_of::<C>() + size_of::<usize>()))
+	}
 
 	/// Clears the `CountMinSketch` data structure, as if it was new.
 	pub fn clear(&mut self) {
 		let config = &self.config;
-		self.counters
-			.iter_mut()
-			.flat_map(|x| x.iter_mut())
-			.for_each(|counter| {
-				*counter = C::new(config);
-			})
+		self.total = self.total.as_ref().map(|_| C::new(config));
+		self.counters.iter_mut().for_each(|counter| {
+			*counter = C::new(config);
+		});
 	}
 
-	fn optimal_width(tolerance: f64) -> usize {
+	fn optimal_width(tolerance: f64) -> Result<usize, Error> {
 		let e = tolerance;
-		let width = f64_to_usize((2.0 / e).round());
-		max(2, width)
-			.checked_next_power_of_two()
-			.expect("Width would be way too large")
+		if e.is_nan() || e <= 0.0 {
+			return Err(Error::InvalidParameter("tolerance must be greater than 0"));
+		}
+		let width = (2.0 / e).round();
+		let max_width = 1_usize << (cmp::min(usize::BITS, 53) - 1);
+		if width > usize_to_f64(max_width) {
+			return Err(Error::InvalidParameter(
+				"tolerance is too small, the width would be way too large",
+			));
+		}
+		Ok(max(2, f64_to_usize(width)).next_power_of_two())
 	}
 
 	fn mask(width: usize) -> usize {
@@ -179,55 +492,98 @@ where
 		width - 1
 	}
 
-	fn optimal_k_num(probability: f64) -> usize {
-		max(
+	fn optimal_k_num(probability: f64) -> Result<usize, Error> {
+		if !(0.0 < probability && probability < 1.0) {
+			return Err(Error::InvalidParameter(
+				"probability must be between 0 and 1",
+			));
+		}
+		Ok(max(
 			1,
 			f64_to_usize(((1.0 - probability).ln() / 0.5_f64.ln()).floor()),
-		)
+		))
 	}
 
-	fn offsets<Q: ?Sized>(&self, key: &Q) -> impl Iterator<Item = usize>
+	fn hash<Q: ?Sized>(&self, key: &Q) -> u64
 	where
 		Q: Hash,
 		K: Borrow<Q>,
 	{
-		let mask = self.mask;
-		hashes(key).map(move |hash| usize::try_from(hash & u64::try_from(mask).unwrap()).unwrap())
+		self.hasher.hash_one(key)
+	}
+
+	/// The indices of the counters of the key with `hash`, one per row.
+	fn offsets(&self, hash: u64) -> impl Iterator<Item = usize> {
+		let (width, depth, layout) = (self.mask + 1, self.k_num, self.layout);
+		let mask = u64::try_from(self.mask).unwrap();
+		let stride = row_stride::<C>(width);
+		let sub = block_width::<C>(width, depth);
+		let mut block = None;
+		hashes(hash)
+			.take(depth)
+			.enumerate()
+			.map(move |(row, hash)| {
+				let column = usize::try_from(hash & mask).unwrap();
+				match layout {
+					CountMinLayout::Rows => row * stride + column,
+					CountMinLayout::Blocked => {
+						// the first hash picks the block, and the high bits of each pick the counter within the row's part of it
+						let block = *block.get_or_insert(column / sub);
+						(block * depth + row) * sub
+							+ (usize::try_from(hash >> 32).unwrap() & (sub - 1))
+					}
+				}
+			})
 	}
 }
 
-fn hashes<Q: ?Sized>(key: &Q) -> impl Iterator<Item = u64>
-where
-	Q: Hash,
-{
-	#[allow(missing_copy_implementations, missing_debug_implementations)]
-	struct X(XxHash);
-	impl Iterator for X {
-		type Item = u64;
-		fn next(&mut self) -> Option<Self::Item> {
-			let ret = self.0.finish();
-			self.0.write(&[123]);
-			Some(ret)
-		}
-	}
-	let mut hasher = XxHash::default();
-	key.hash(&mut hasher);
-	X(hasher)
+/// The hashes of each row for a key with hash `x`, derived by double hashing as `h1 + i·h2`.
+///
+/// Only the one 64 bit hash of the key is needed: `h1` is `x` itself, and `h2` is `x` scrambled by the MurmurHash3 finalizer. So `h2` is a fixed function of `h1` rather than independent of it, though the finalizer leaves no simple relation between the two, and keys whose hashes collide collide in every row. `h2` is kept from being zero, which would put every row in the same column. See [*Less Hashing, Same Performance: Building a Better Bloom Filter*](https://www.eecs.harvard.edu/~michaelm/postscripts/rsa2008.pdf) by Kirsch and Mitzenmacher.
+pub(crate) fn hashes(x: u64) -> impl Iterator<Item = u64> {
+	let h1 = x;
+	let h2 = fmix64(x ^ 0x9e37_79b9_7f4a_7c15).max(1);
+	iter::successors(Some(h1), move |h| Some(h.wrapping_add(h2)))
 }
 
-impl<K: ?Sized, C: New + Clone> Clone for CountMinSketch<K, C> {
+impl<K: ?Sized, C: New + Clone, S: Clone> Clone for CountMinSketch<K, C, S> {
 	fn clone(&self) -> Self {
 		Self {
 			counters: self.counters.clone(),
 			offsets: vec![0; self.offsets.len()],
 			mask: self.mask,
 			k_num: self.k_num,
+			layout: self.layout,
 			config: self.config.clone(),
+			total: self.total.clone(),
+			hasher: self.hasher.clone(),
 			marker: PhantomData,
 		}
 	}
 }
-impl<K: ?Sized, C: New> fmt::Debug for CountMinSketch<K, C> {
+impl<'a, K: ?Sized, C, S> UnionAssign<&'a CountMinSketch<K, C, S>> for CountMinSketch<K, C, S>
+where
+	K: Hash,
+	C: New + for<'b> UnionAssign<&'b C> + Intersect,
+	C::Config: PartialEq,
+	S: BuildHasher + PartialEq,
+{
+	fn union_assign(&mut self, rhs: &'a Self) {
+		self.union(rhs);
+	}
+}
+impl<'a, K: ?Sized, C, S> ops::AddAssign<&'a CountMinSketch<K, C, S>> for CountMinSketch<K, C, S>
+where
+	K: Hash,
+	C: New + for<'b> UnionAssign<&'b C> + Intersect + for<'b> ops::AddAssign<&'b C>,
+	C::Config: PartialEq,
+	S: BuildHasher + PartialEq,
+{
+	fn add_assign(&mut self, rhs: &'a Self) {
+		self.try_add(rhs).unwrap_or_else(|err| panic!("{}", err));
+	}
+}
+impl<K: ?Sized, C: New, S> fmt::Debug for CountMinSketch<K, C, S> {
 	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
 		fmt.debug_struct("CountMinSketch")
 			// .field("counters", &self.counters)
@@ -237,6 +593,10 @@ impl<K: ?Sized, C: New> fmt::Debug for CountMinSketch<K, C> {
 
 #[cfg(test)]
 mod tests {
+	use super::CountMinLayout;
+	use crate::{error::Error, hash::XxHashBuilder, traits::UnionAssign, u64_to_f64};
+	use std::{convert::TryFrom, hash::BuildHasher, mem::size_of};
+
 	type CountMinSketch8<K> = super::CountMinSketch<K, u8>;
 	type CountMinSketch16<K> = super::CountMinSketch<K, u16>;
 	type CountMinSketch64<K> = super::CountMinSketch<K, u64>;
@@ -275,4 +635,232 @@ mod tests {
 		//     assert!(cms.get(&key) < 11_000);
 		// }
 	}
+
+	#[test]
+	fn test_hasher() {
+		let mut cms1 = CountMinSketch64::<u64>::with_hasher(
+			0.99,
+			2.0 / 100.0,
+			(),
+			XxHashBuilder::with_seed(1),
+		);
+		let mut cms2 = CountMinSketch64::<u64>::with_hasher(
+			0.99,
+			2.0 / 100.0,
+			(),
+			XxHashBuilder::with_seed(2),
+		);
+		for i in 0..1_000 {
+			let _ = cms1.push(&i, &1);
+			let _ = cms2.push(&i, &1);
+		}
+		assert_ne!(cms1.counters, cms2.counters);
+		for key in 0..1_000 {
+			assert!(cms1.get(&key) >= 1);
+			assert!(cms2.get(&key) >= 1);
+		}
+	}
+
+	#[test]
+	fn test_get_with_bounds() {
+		let mut cms = CountMinSketch64::<u64>::new(0.99, 2.0 / 100.0, ());
+		let _ = cms.push(&0, &1);
+		assert!(!cms.track_total());
+		assert_eq!(cms.get_with_bounds(&0).lower, 0.0);
+		cms.set_track_total(true);
+		assert_eq!(cms.get(&0), 0);
+		let bounds = cms.get_with_bounds(&0);
+		assert_eq!(
+			(bounds.lower, bounds.estimate, bounds.upper),
+			(0.0, 0.0, 0.0)
+		);
+		for i in 0..100_000 {
+			let _ = cms.push(&(i % 1000), &(i % 7));
+		}
+		let total = (0..100_000).map(|i| i % 7).sum::<u64>();
+		assert_eq!(cms.total, Some(total));
+		for key in 0..1000 {
+			let actual = u64_to_f64((key..100_000).step_by(1000).map(|i| i % 7).sum());
+			let bounds = cms.get_with_bounds(&key);
+			assert_eq!(bounds.estimate, u64_to_f64(cms.get(&key)));
+			assert_eq!(bounds.upper, bounds.estimate);
+			assert_eq!(
+				bounds.lower,
+				(bounds.estimate - 2.0 / 128.0 * u64_to_f64(total)).max(0.0)
+			);
+			assert!(bounds.lower <= actual && actual <= bounds.upper);
+		}
+		cms.clear();
+		assert_eq!(cms.get_with_bounds(&0).lower, 0.0);
+		assert!(cms.track_total());
+
+		// tracking is opt-in, as the sum can overflow counters that the values don't
+		let mut cms = CountMinSketch8::<u64>::new(0.99, 2.0 / 100.0, ());
+		for i in 0..1_000 {
+			let _ = cms.push(&i, &1);
+		}
+		assert!(cms.get(&0) >= 1);
+	}
+
+	#[test]
+	fn test_merge() {
+		let mut shards = (0..2)
+			.map(|_| {
+				let mut cms = CountMinSketch64::<u64>::new(0.99, 2.0 / 100.0, ());
+				cms.set_track_total(true);
+				cms
+			})
+			.collect::<Vec<_>>();
+		for i in 0..100_000 {
+			let _ = shards[usize::try_from(i / 1000 % 2).unwrap()].push(&(i % 1000), &1);
+		}
+		let mut sum = shards[0].clone();
+		sum += &shards[1];
+		assert_eq!(sum.total, Some(100_000));
+		let mut partial = CountMinSketch64::<u64>::new(0.99, 2.0 / 100.0, ());
+		partial += &shards[0];
+		assert_eq!(partial.total, None);
+		let mut union = shards[0].clone();
+		UnionAssign::union_assign(&mut union, &shards[1]);
+		for key in 0..1000 {
+			let (a, b) = (shards[0].get(&key), shards[1].get(&key));
+			assert!(a >= 50 && b >= 50 && sum.get(&key) >= a + b);
+			assert!(union.get(&key) >= a.max(b) && union.get(&key) <= sum.get(&key));
+			let bounds = sum.get_with_bounds(&key);
+			assert!(bounds.lower <= 100.0 && 100.0 <= bounds.upper);
+		}
+		union.union(&union.clone());
+		assert_eq!(union.counters, {
+			let mut x = shards[0].clone();
+			x.union(&shards[1]);
+			x.counters
+		});
+		let other = CountMinSketch64::<u64>::new(0.99, 1.0 / 100.0, ());
+		assert!(matches!(sum.try_add(&other), Err(Error::Incompatible(_))));
+		assert!(matches!(
+			sum.try_union(&CountMinSketch64::new(0.9, 2.0 / 100.0, ())),
+			Err(Error::Incompatible(_))
+		));
+		let seeded =
+			CountMinSketch64::with_hasher(0.99, 2.0 / 100.0, (), XxHashBuilder::with_seed(1));
+		assert!(matches!(sum.try_add(&seeded), Err(Error::Incompatible(_))));
+		assert!(matches!(
+			sum.try_union(&seeded),
+			Err(Error::Incompatible(_))
+		));
+	}
+
+	#[test]
+	fn test_dimensions() {
+		let cms = CountMinSketch64::<u64>::new(0.99, 2.0 / 100.0, ());
+		assert_eq!((cms.width(), cms.depth()), (128, 6));
+		assert_eq!(
+			CountMinSketch64::<u64>::estimate_memory(0.99, 2.0 / 100.0),
+			Ok(6 * (128 * 8 + size_of::<usize>()))
+		);
+		let mut explicit = CountMinSketch64::<u64>::with_dimensions(128, 6, ());
+		assert_eq!((explicit.width(), explicit.depth()), (128, 6));
+		for i in 0..1000 {
+			let _ = explicit.push(&i, &1);
+		}
+		explicit.union(&cms);
+		for &(width, depth) in &[(0, 1), (1, 1), (100, 1), (128, 0)] {
+			assert!(matches!(
+				CountMinSketch64::<u64>::try_with_dimensions(width, depth, ()),
+				Err(Error::InvalidParameter(_))
+			));
+		}
+		assert!(matches!(
+			CountMinSketch64::<u64>::estimate_memory(1.0, 0.1),
+			Err(Error::InvalidParameter(_))
+		));
+	}
+
+	#[test]
+	fn test_layouts() {
+		// rows of u8 are padded to a cache line, and start on one
+		let cms = CountMinSketch8::<u64>::with_dimensions(16, 3, ());
+		assert_eq!(cms.counters.len(), 3 * 64);
+		assert_eq!(cms.counters.as_ptr().align_offset(64), 0);
+		assert!(cms
+			.offsets(cms.hash(&0))
+			.enumerate()
+			.all(|(row, offset)| offset / 64 == row));
+		assert_eq!(cms.clone().counters.as_ptr().align_offset(64), 0);
+		let mut blocked = CountMinSketch64::<u64>::new(0.99, 2.0 / 100.0, ());
+		blocked.set_layout(CountMinLayout::Blocked);
+		assert_eq!(blocked.counters.len(), 128 * 6);
+		for key in 0..1000 {
+			let offsets = blocked.offsets(blocked.hash(&key)).collect::<Vec<_>>();
+			assert_eq!(offsets.len(), 6);
+			assert!(offsets.windows(2).all(|w| w[0] < w[1]));
+			assert!((offsets[5] - offsets[0]) * size_of::<u64>() < 2 * 64);
+		}
+		for i in 0..100_000 {
+			let _ = blocked.push(&(i % 1000), &1);
+		}
+		for key in 0..1000 {
+			assert!(blocked.get(&key) >= 100);
+		}
+		let rows = CountMinSketch64::<u64>::new(0.99, 2.0 / 100.0, ());
+		assert!(matches!(
+			blocked.clone().try_union(&rows),
+			Err(Error::Incompatible(_))
+		));
+		blocked.set_layout(CountMinLayout::Rows);
+		assert_eq!(blocked.get(&0), 0);
+		assert!(blocked.try_union(&rows).is_ok());
+	}
+
+	#[test]
+	fn test_hashed() {
+		let mut cms = CountMinSketch64::<u64>::new(0.99, 2.0 / 100.0, ());
+		let hash = |key: u64| XxHashBuilder::default().hash_one(key);
+		for i in 0..10_000 {
+			if i % 2 == 0 {
+				let _ = cms.push(&(i % 100), &1);
+			} else {
+				let _ = cms.push_hashed(hash(i % 100), &1);
+			}
+		}
+		for key in 0..100 {
+			assert_eq!(cms.get_hashed(hash(key)), cms.get(&key));
+			assert!(cms.get(&key) >= 100);
+		}
+		// the one key hash whose `h2` would be zero
+		let x = 0x9e37_79b9_7f4a_7c15;
+		assert_eq!(
+			super::hashes(x).take(3).collect::<Vec<_>>(),
+			[x, x + 1, x + 2]
+		);
+	}
+
+	#[test]
+	fn test_deserialize() {
+		let cms = CountMinSketch64::<u64>::with_dimensions(4, 4, ());
+		let json = serde_json::to_string(&cms).unwrap();
+		let copy: CountMinSketch64<u64> = serde_json::from_str(&json).unwrap();
+		assert_eq!(copy.counters, cms.counters);
+		// a width and depth whose product overflows
+		let json = json.replace("\"mask\":3", &format!("\"mask\":{}", (1_u64 << 62) - 1));
+		assert!(serde_json::from_str::<CountMinSketch64<u64>>(&json).is_err());
+	}
+
+	#[test]
+	fn test_fallible() {
+		for &(probability, tolerance) in &[
+			(0.0, 0.1),
+			(1.0, 0.1),
+			(f64::NAN, 0.1),
+			(0.9, 0.0),
+			(0.9, -1.0),
+			(0.9, 1e-300),
+		] {
+			assert!(matches!(
+				CountMinSketch64::<u64>::try_new(probability, tolerance, ()),
+				Err(Error::InvalidParameter(_))
+			));
+		}
+		assert!(CountMinSketch64::<u64>::try_new(0.99, 2.0 / 100.0, ()).is_ok());
+	}
 }
@@ -0,0 +1,103 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+	alloc::{self, Layout}, fmt, mem::{align_of, size_of}, ops, ptr::{self, NonNull}, slice
+};
+
+/// The size of a cache line, which the counters of a [`CountMinSketch`](super::CountMinSketch) are aligned to.
+pub const CACHE_LINE: usize = 64;
+
+/// A boxed slice whose allocation is aligned to [`CACHE_LINE`], so that padding each row of counters to whole cache lines also starts each row on one.
+///
+/// It serializes as a plain sequence.
+pub struct Aligned<T> {
+	ptr: NonNull<T>,
+	len: usize,
+}
+impl<T> Aligned<T> {
+	/// Move the elements of `vec` into an aligned allocation.
+	pub fn from_vec(mut vec: Vec<T>) -> Self {
+		let len = vec.len();
+		let layout = Self::layout(len);
+		let ptr = if layout.size() == 0 {
+			NonNull::dangling()
+		} else {
+			let ptr = unsafe { alloc::alloc(layout) }.cast::<T>();
+			NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout))
+		};
+		unsafe {
+			ptr::copy_nonoverlapping(vec.as_ptr(), ptr.as_ptr(), len);
+			// the elements have been moved out, so `vec` only frees its allocation
+			vec.set_len(0);
+		}
+		Self { ptr, len }
+	}
+
+	fn layout(len: usize) -> Layout {
+		Layout::array::<T>(len)
+			.and_then(|layout| layout.align_to(CACHE_LINE.max(align_of::<T>())))
+			.unwrap_or_else(|_| panic!("capacity overflow"))
+	}
+}
+impl<T> ops::Deref for Aligned<T> {
+	type Target = [T];
+
+	fn deref(&self) -> &[T] {
+		unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+	}
+}
+impl<T> ops::DerefMut for Aligned<T> {
+	fn deref_mut(&mut self) -> &mut [T] {
+		unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+	}
+}
+impl<T> Drop for Aligned<T> {
+	fn drop(&mut self) {
+		let layout = Self::layout(self.len);
+		unsafe {
+			ptr::drop_in_place(ptr::slice_from_raw_parts_mut(self.ptr.as_ptr(), self.len));
+			if layout.size() != 0 {
+				alloc::dealloc(self.ptr.as_ptr().cast(), layout);
+			}
+		}
+	}
+}
+// it owns its elements, like a `Box<[T]>`
+unsafe impl<T: Send> Send for Aligned<T> {}
+unsafe impl<T: Sync> Sync for Aligned<T> {}
+impl<T: Clone> Clone for Aligned<T> {
+	fn clone(&self) -> Self {
+		Self::from_vec(self.to_vec())
+	}
+}
+impl<T: PartialEq> PartialEq for Aligned<T> {
+	fn eq(&self, other: &Self) -> bool {
+		**self == **other
+	}
+}
+impl<T: fmt::Debug> fmt::Debug for Aligned<T> {
+	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+		(**self).fmt(fmt)
+	}
+}
+impl<T: Serialize> Serialize for Aligned<T> {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		(**self).serialize(serializer)
+	}
+}
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Aligned<T> {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		Vec::deserialize(deserializer).map(Self::from_vec)
+	}
+}
+
+/// The number of counters of type `C` per row of `width`, which is padded so that a row fills whole cache lines.
+///
+/// `width` is a power of two, and so is the fewest counters that fill whole cache lines, so this is the larger of the two.
+pub fn row_stride<C>(width: usize) -> usize {
+	let size = size_of::<C>();
+	if size == 0 {
+		return width;
+	}
+	let lanes = CACHE_LINE >> size.trailing_zeros().min(CACHE_LINE.trailing_zeros());
+	width.max(lanes)
+}
@@ -0,0 +1,526 @@
+use serde::{Deserialize, Serialize};
+use std::{
+	borrow::Borrow, cmp::Ordering, convert::TryFrom, fmt, hash::{BuildHasher, Hash}, marker::PhantomData, ops
+};
+
+use super::{f64_to_usize, usize_to_f64};
+use crate::{count_min::hashes, error::Error, hash::XxHashBuilder, traits::ToF64};
+
+/// An implementation of a [count sketch](https://en.wikipedia.org/wiki/Count_sketch) data structure.
+///
+/// Like a [`CountMinSketch`](crate::CountMinSketch) each key maps to one counter per row, but each row also gives the key a sign of `±1` that its values are multiplied by. Collisions then cancel out in expectation rather than accumulating, so the estimate of each row is unbiased and values can be negative, i.e. it supports *turnstile* updates. The estimate of a key is the median of the rows' estimates.
+///
+/// See [*Finding frequent items in data streams*](https://www.cs.princeton.edu/courses/archive/spring04/cos598B/bib/CharikarCF.pdf) by Charikar, Chen and Farach-Colton for background.
+///
+/// Keys are hashed with `S`, which defaults to [`XxHashBuilder`] with a seed of `0`.
+#[derive(Serialize, Deserialize)]
+#[serde(
+	bound(serialize = "S: Serialize", deserialize = "S: Deserialize<'de>"),
+	try_from = "CountSketchUnchecked<K, S>"
+)]
+pub struct CountSketch<K: ?Sized, S = XxHashBuilder> {
+	counters: Vec<i64>,
+	mask: usize,
+	depth: usize,
+	hasher: S,
+	marker: PhantomData<fn(K)>,
+}
+
+/// A deserialized [`CountSketch`] that hasn't yet been checked to be valid.
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "S: Deserialize<'de>"))]
+struct CountSketchUnchecked<K: ?Sized, S> {
+	counters: Vec<i64>,
+	mask: usize,
+	depth: usize,
+	hasher: S,
+	marker: PhantomData<fn(K)>,
+}
+impl<K: ?Sized, S> TryFrom<CountSketchUnchecked<K, S>> for CountSketch<K, S> {
+	type Error = Error;
+
+	fn try_from(cs: CountSketchUnchecked<K, S>) -> Result<Self, Error> {
+		let CountSketchUnchecked {
+			counters,
+			mask,
+			depth,
+			hasher,
+			..
+		} = cs;
+		let width = mask.wrapping_add(1);
+		if depth == 0
+			|| width < 2
+			|| width & mask != 0
+			|| width.checked_mul(depth) != Some(counters.len())
+		{
+			return Err(Error::Deserialization("CountSketch counters are malformed"));
+		}
+		Ok(Self {
+			counters,
+			mask,
+			depth,
+			hasher,
+			marker: PhantomData,
+		})
+	}
+}
+
+impl<K: ?Sized> CountSketch<K>
+where
+	K: Hash,
+{
+	/// Create an empty `CountSketch` data structure with the specified error tolerance.
+	///
+	/// With probability at least `probability`, each estimate is within `tolerance·‖f‖₂` of the true value, where `‖f‖₂` is the L2 norm of the values of all keys.
+	pub fn new(probability: f64, tolerance: f64) -> Self {
+		Self::with_hasher(probability, tolerance, XxHashBuilder::default())
+	}
+
+	/// Like [`new`](Self::new), but returns an error if `probability` or `tolerance` are invalid.
+	pub fn try_new(probability: f64, tolerance: f64) -> Result<Self, Error> {
+		Self::try_with_hasher(probability, tolerance, XxHashBuilder::default())
+	}
+
+	/// Create an empty `CountSketch` data structure with `depth` rows of `width` counters.
+	pub fn with_dimensions(width: usize, depth: usize) -> Self {
+		Self::with_dimensions_and_hasher(width, depth, XxHashBuilder::default())
+	}
+
+	/// Like [`with_dimensions`](Self::with_dimensions), but returns an error if `width` or `depth` are invalid.
+	pub fn try_with_dimensions(width: usize, depth: usize) -> Result<Self, Error> {
+		Self::try_with_dimensions_and_hasher(width, depth, XxHashBuilder::default())
+	}
+}
+
+impl<K: ?Sized, S> CountSketch<K, S>
+where
+	K: Hash,
+	S: BuildHasher,
+{
+	/// Create an empty `CountSketch` data structure with the specified error tolerance, that hashes keys with `hasher`.
+	///
+	/// # Panics
+	///
+	/// Panics if `probability` or `tolerance` are invalid, as for [`try_with_hasher`](Self::try_with_hasher).
+	pub fn with_hasher(probability: f64, tolerance: f64, hasher: S) -> Self {
+		Self::try_with_hasher(probability, tolerance, hasher)
+			.unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`with_hasher`](Self::with_hasher), but returns an error if `probability` or `tolerance` are invalid.
+	pub fn try_with_hasher(probability: f64, tolerance: f64, hasher: S) -> Result<Self, Error> {
+		let width = Self::optimal_width(tolerance)?;
+		let depth = Self::optimal_depth(probability)?;
+		Self::try_with_dimensions_and_hasher(width, depth, hasher)
+	}
+
+	/// Create an empty `CountSketch` data structure with `depth` rows of `width` counters, that hashes keys with `hasher`.
+	///
+	/// `width` must be a power of two, so that keys can be mapped to counters by masking their hashes.
+	///
+	/// # Panics
+	///
+	/// Panics if `width` or `depth` are invalid, as for [`try_with_dimensions_and_hasher`](Self::try_with_dimensions_and_hasher).
+	pub fn with_dimensions_and_hasher(width: usize, depth: usize, hasher: S) -> Self {
+		Self::try_with_dimensions_and_hasher(width, depth, hasher)
+			.unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`with_dimensions_and_hasher`](Self::with_dimensions_and_hasher), but returns an error if `width` isn't a power of two of at least 2, or `depth` is 0.
+	pub fn try_with_dimensions_and_hasher(
+		width: usize, depth: usize, hasher: S,
+	) -> Result<Self, Error> {
+		if width < 2 || !width.is_power_of_two() {
+			return Err(Error::InvalidParameter(
+				"width must be a power of two of at least 2",
+			));
+		}
+		if depth == 0 {
+			return Err(Error::InvalidParameter("depth must be at least 1"));
+		}
+		Ok(Self {
+			counters: vec![0; width * depth],
+			mask: width - 1,
+			depth,
+			hasher,
+			marker: PhantomData,
+		})
+	}
+
+	/// Add `value` to the value of `key`. `value` can be negative.
+	///
+	/// Counters wrap around on overflow, so values that overflow `i64` only give meaningful estimates once they've come back within range.
+	pub fn push<Q>(&mut self, key: &Q, value: i64)
+	where
+		Q: ?Sized + Hash,
+		K: Borrow<Q>,
+	{
+		self.push_hashed(self.hash(key), value);
+	}
+
+	/// Add `value` to the value of a key, given its hash.
+	///
+	/// `hash` must be the result of hashing the key with the `CountSketch`'s hasher.
+	pub fn push_hashed(&mut self, hash: u64, value: i64) {
+		for (offset, sign) in self.offsets(hash) {
+			let counter = &mut self.counters[offset];
+			*counter = counter.wrapping_add(value.wrapping_mul(sign));
+		}
+	}
+
+	/// Retrieve an estimate of the value of `key`, the median of the rows' estimates.
+	pub fn get<Q>(&self, key: &Q) -> i64
+	where
+		Q: ?Sized + Hash,
+		K: Borrow<Q>,
+	{
+		self.get_hashed(self.hash(key))
+	}
+
+	/// Retrieve an estimate of the value of a key, given its hash as for [`push_hashed`](Self::push_hashed).
+	///
+	/// This is the median of the rows' estimates, or with an even [`depth`](Self::depth) the mean of the middle two rounded down. Each row's estimate is unbiased, but their median needn't be. An estimate above `i64::MAX` saturates to it.
+	pub fn get_hashed(&self, hash: u64) -> i64 {
+		let estimates = self
+			.offsets(hash)
+			.map(|(offset, sign)| i128::from(sign) * i128::from(self.counters[offset]))
+			.collect::<Vec<_>>();
+		// only a single row's estimate of `-i64::MIN` can be out of range
+		i64::try_from(median(estimates, |a, b| a + (b - a) / 2)).unwrap_or(i64::MAX)
+	}
+
+	/// Estimate the inner product of the values of `a` and `b`, i.e. the sum over all keys of the product of their values in each.
+	///
+	/// This is the median over rows of the dot products of the rows. Both must have been created with the same dimensions and hasher.
+	///
+	/// # Panics
+	///
+	/// Panics if the dimensions or hashers differ, as for [`try_inner_product`](Self::try_inner_product).
+	pub fn inner_product(a: &Self, b: &Self) -> f64
+	where
+		S: PartialEq,
+	{
+		Self::try_inner_product(a, b).unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`inner_product`](Self::inner_product), but returns an error if the dimensions or hashers differ.
+	pub fn try_inner_product(a: &Self, b: &Self) -> Result<f64, Error>
+	where
+		S: PartialEq,
+	{
+		a.check_compatible(b)?;
+		Ok(Self::dot(a, b))
+	}
+
+	/// Estimate the L2 norm of the values of all keys, i.e. the square root of the sum of their squares.
+	pub fn l2_norm(&self) -> f64 {
+		Self::dot(self, self).sqrt()
+	}
+
+	/// The median over rows of the dot products of the rows of `a` and `b`, which must be compatible.
+	fn dot(a: &Self, b: &Self) -> f64 {
+		let width = a.width();
+		let products = a
+			.counters
+			.chunks(width)
+			.zip(b.counters.chunks(width))
+			.map(|(a, b)| {
+				a.iter()
+					.zip(b)
+					.map(|(&a, &b)| i128::from(a) * i128::from(b))
+					.sum::<i128>()
+					.to_f64()
+			})
+			.collect::<Vec<_>>();
+		median(products, |a, b| a + (b - a) / 2.0)
+	}
+
+	/// Add another `CountSketch` to this one, so that the value of each key is the sum of its values in both.
+	///
+	/// Both must have been created with the same dimensions and hasher, e.g. to sketch the partitions of a dataset separately.
+	pub fn try_add(&mut self, src: &Self) -> Result<(), Error>
+	where
+		S: PartialEq,
+	{
+		self.check_compatible(src)?;
+		for (counter, &src_counter) in self.counters.iter_mut().zip(&src.counters) {
+			*counter = counter.wrapping_add(src_counter);
+		}
+		Ok(())
+	}
+
+	/// Subtract another `CountSketch` from this one, so that the value of each key is the difference of its values in each.
+	pub fn try_sub(&mut self, src: &Self) -> Result<(), Error>
+	where
+		S: PartialEq,
+	{
+		self.check_compatible(src)?;
+		for (counter, &src_counter) in self.counters.iter_mut().zip(&src.counters) {
+			*counter = counter.wrapping_sub(src_counter);
+		}
+		Ok(())
+	}
+
+	/// The number of counters in each row.
+	pub fn width(&self) -> usize {
+		self.mask + 1
+	}
+
+	/// The number of rows, i.e. the number of counters each key maps to.
+	pub fn depth(&self) -> usize {
+		self.depth
+	}
+
+	/// Clears the `CountSketch` data structure, as if it was new.
+	pub fn clear(&mut self) {
+		self.counters.iter_mut().for_each(|counter| *counter = 0);
+	}
+
+	/// Per row, the variance of an estimate is at most `‖f‖₂²/width`, so by Chebyshev's inequality a width of `16/tolerance²` makes a row miss by more than `tolerance·‖f‖₂` with probability at most `1/16`.
+	fn optimal_width(tolerance: f64) -> Result<usize, Error> {
+		let e = tolerance;
+		if e.is_nan() || e <= 0.0 {
+			return Err(Error::InvalidParameter("tolerance must be greater than 0"));
+		}
+		let width = (16.0 / (e * e)).ceil();
+		let max_width = 1_usize << (usize::BITS.min(53) - 1);
+		if width > usize_to_f64(max_width) {
+			return Err(Error::InvalidParameter(
+				"tolerance is too small, the width would be way too large",
+			));
+		}
+		Ok(f64_to_usize(width).max(2).next_power_of_two())
+	}
+
+	/// The median misses only if at least half of the rows do, which with rows that miss with probability at most `1/16` happens with probability at most `2^depth·(1/16)^(depth/2) = 2^-depth`.
+	fn optimal_depth(probability: f64) -> Result<usize, Error> {
+		if !(0.0 < probability && probability < 1.0) {
+			return Err(Error::InvalidParameter(
+				"probability must be between 0 and 1",
+			));
+		}
+		Ok(f64_to_usize((-(1.0 - probability).log2()).ceil()).max(1))
+	}
+
+	fn check_compatible(&self, src: &Self) -> Result<(), Error>
+	where
+		S: PartialEq,
+	{
+		if self.mask != src.mask || self.depth != src.depth {
+			return Err(Error::Incompatible(
+				"CountSketches must have the same width and depth to be combined",
+			));
+		}
+		if self.hasher != src.hasher {
+			return Err(Error::Incompatible(
+				"CountSketches must have the same hasher to be combined",
+			));
+		}
+		Ok(())
+	}
+
+	fn hash<Q>(&self, key: &Q) -> u64
+	where
+		Q: ?Sized + Hash,
+		K: Borrow<Q>,
+	{
+		self.hasher.hash_one(key)
+	}
+
+	/// The index and sign of the counter of the key with `hash` in each row.
+	///
+	/// The column comes from the low bits of the row's hash and the sign from the top bit.
+	fn offsets(&self, hash: u64) -> impl Iterator<Item = (usize, i64)> {
+		let (width, mask) = (self.width(), u64::try_from(self.mask).unwrap());
+		hashes(hash)
+			.take(self.depth)
+			.enumerate()
+			.map(move |(row, hash)| {
+				let column = usize::try_from(hash & mask).unwrap();
+				(
+					row * width + column,
+					1 - 2 * i64::try_from(hash >> 63).unwrap(),
+				)
+			})
+	}
+}
+
+/// The median of `xs`, taking the `mean` of the middle two if there's an even number.
+fn median<T: Copy + PartialOrd>(mut xs: Vec<T>, mean: impl FnOnce(T, T) -> T) -> T {
+	xs.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+	let mid = xs.len() / 2;
+	if xs.len() % 2 == 1 {
+		xs[mid]
+	} else {
+		mean(xs[mid - 1], xs[mid])
+	}
+}
+
+impl<K: ?Sized, S: Clone> Clone for CountSketch<K, S> {
+	fn clone(&self) -> Self {
+		Self {
+			counters: self.counters.clone(),
+			mask: self.mask,
+			depth: self.depth,
+			hasher: self.hasher.clone(),
+			marker: PhantomData,
+		}
+	}
+}
+impl<K: ?Sized, S> fmt::Debug for CountSketch<K, S> {
+	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+		fmt.debug_struct("CountSketch")
+			.field("width", &(self.mask + 1))
+			.field("depth", &self.depth)
+			.finish_non_exhaustive()
+	}
+}
+impl<'a, K: ?Sized, S> ops::AddAssign<&'a CountSketch<K, S>> for CountSketch<K, S>
+where
+	K: Hash,
+	S: BuildHasher + PartialEq,
+{
+	fn add_assign(&mut self, rhs: &'a Self) {
+		self.try_add(rhs).unwrap_or_else(|err| panic!("{}", err));
+	}
+}
+impl<'a, K: ?Sized, S> ops::SubAssign<&'a CountSketch<K, S>> for CountSketch<K, S>
+where
+	K: Hash,
+	S: BuildHasher + PartialEq,
+{
+	fn sub_assign(&mut self, rhs: &'a Self) {
+		self.try_sub(rhs).unwrap_or_else(|err| panic!("{}", err));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::CountSketch;
+	use crate::{error::Error, hash::XxHashBuilder, traits::ToF64};
+	use std::convert::TryFrom;
+
+	#[test]
+	fn test_turnstile() {
+		let mut cs = CountSketch::<u64>::new(0.99, 0.05);
+		assert_eq!((cs.width(), cs.depth()), (8192, 7));
+		let mut actual = vec![0_i64; 1000];
+		for i in 0..100_000_u64 {
+			let key = i * 7919 % 1000;
+			let value = if i % 3 == 0 { -2 } else { 1 };
+			cs.push(&key, value);
+			actual[usize::try_from(key).unwrap()] += value;
+		}
+		let norm = actual.iter().map(|&x| (x * x).to_f64()).sum::<f64>().sqrt();
+		let mut error_sum = 0;
+		for (key, &actual) in actual.iter().enumerate() {
+			let error = cs.get(&u64::try_from(key).unwrap()) - actual;
+			assert!(error.to_f64().abs() <= 0.05 * norm, "{} {}", key, error);
+			error_sum += error;
+		}
+		// unbiased, so the errors mostly cancel out
+		assert!((error_sum.to_f64() / 1000.0).abs() < 1.0);
+		assert!((cs.l2_norm() - norm).abs() <= 0.05 * norm);
+		cs.clear();
+		assert_eq!(cs.get(&0), 0);
+	}
+
+	#[test]
+	fn test_merge() {
+		let mut a = CountSketch::<u64>::with_dimensions(1024, 4);
+		let mut b = CountSketch::<u64>::with_dimensions(1024, 4);
+		let mut both = CountSketch::<u64>::with_dimensions(1024, 4);
+		let (mut actual_a, mut actual_b) = (vec![0_i64; 100], vec![0_i64; 100]);
+		for i in 0..10_000_u64 {
+			let key = i % 100;
+			let value = i64::try_from(i % 7).unwrap() - 3;
+			if i / 100 % 2 == 0 {
+				a.push(&key, value);
+				actual_a[usize::try_from(key).unwrap()] += value;
+			} else {
+				b.push(&key, value);
+				actual_b[usize::try_from(key).unwrap()] += value;
+			}
+			both.push(&key, value);
+		}
+		let inner_product = actual_a
+			.iter()
+			.zip(&actual_b)
+			.map(|(&a, &b)| a * b)
+			.sum::<i64>()
+			.to_f64();
+		let estimate = CountSketch::inner_product(&a, &b);
+		let norm = |xs: &[i64]| xs.iter().map(|&x| (x * x).to_f64()).sum::<f64>().sqrt();
+		// width 1024 gives a tolerance of sqrt(16/1024) of the product of the norms
+		assert!((estimate - inner_product).abs() <= 0.125 * norm(&actual_a) * norm(&actual_b));
+		assert!((a.l2_norm() - norm(&actual_a)).abs() <= 0.125 * norm(&actual_a));
+		let mut sum = a.clone();
+		sum += &b;
+		assert_eq!(sum.counters, both.counters);
+		sum -= &b;
+		assert_eq!(sum.counters, a.counters);
+		assert!(matches!(
+			a.try_add(&CountSketch::with_dimensions(1024, 5)),
+			Err(Error::Incompatible(_))
+		));
+		assert!(matches!(
+			CountSketch::try_inner_product(&a, &CountSketch::with_dimensions(512, 4)),
+			Err(Error::Incompatible(_))
+		));
+		let seeded = CountSketch::with_dimensions_and_hasher(1024, 4, XxHashBuilder::with_seed(1));
+		assert!(matches!(a.try_add(&seeded), Err(Error::Incompatible(_))));
+		assert!(matches!(a.try_sub(&seeded), Err(Error::Incompatible(_))));
+		assert!(matches!(
+			CountSketch::try_inner_product(&a, &seeded),
+			Err(Error::Incompatible(_))
+		));
+	}
+
+	#[test]
+	fn test_overflow() {
+		let mut cs = CountSketch::<u64>::with_dimensions(64, 5);
+		cs.push(&0, i64::MIN);
+		assert!(matches!(cs.get(&0), i64::MIN | i64::MAX));
+		cs.push(&0, i64::MAX);
+		assert_eq!(cs.get(&0), -1);
+		let mut sum = cs.clone();
+		sum += &cs;
+		sum -= &cs;
+		assert_eq!(sum.get(&0), -1);
+	}
+
+	#[test]
+	fn test_deserialize() {
+		let cs = CountSketch::<u64>::with_dimensions(4, 4);
+		let json = serde_json::to_string(&cs).unwrap();
+		let copy: CountSketch<u64> = serde_json::from_str(&json).unwrap();
+		assert_eq!(copy.counters, cs.counters);
+		// a width and depth whose product overflows
+		let json = json.replace("\"mask\":3", &format!("\"mask\":{}", (1_u64 << 62) - 1));
+		assert!(serde_json::from_str::<CountSketch<u64>>(&json).is_err());
+	}
+
+	#[test]
+	fn test_fallible() {
+		for &(probability, tolerance) in &[
+			(0.0, 0.1),
+			(1.0, 0.1),
+			(f64::NAN, 0.1),
+			(0.9, 0.0),
+			(0.9, -1.0),
+			(0.9, 1e-300),
+		] {
+			assert!(matches!(
+				CountSketch::<u64>::try_new(probability, tolerance),
+				Err(Error::InvalidParameter(_))
+			));
+		}
+		for &(width, depth) in &[(0, 1), (1, 1), (100, 1), (128, 0)] {
+			assert!(matches!(
+				CountSketch::<u64>::try_with_dimensions(width, depth),
+				Err(Error::InvalidParameter(_))
+			));
+		}
+		assert!(CountSketch::<u64>::try_new(0.99, 0.1).is_ok());
+	}
+}
@@ -43,85 +43,156 @@
 // https://github.com/twitter/algebird/blob/5fdb079447271a5fe0f1fba068e5f86591ccde36/algebird-core/src/main/scala/com/twitter/algebird/HyperLogLog.scala
 // https://spark.apache.org/docs/latest/api/scala/index.html#org.apache.spark.rdd.RDD countApproxDistinct
 // is_x86_feature_detected ?
-use rand::prelude::random;
-
 use serde::{Deserialize, Serialize};
 use std::{
-	cmp::{self, Ordering}, convert::{identity, TryFrom}, fmt, hash::{Hash, Hasher}, marker::PhantomData, ops::{self, Range}
+	borrow::Cow, cmp::{self, Ordering}, convert::{identity, TryFrom}, fmt, hash::{BuildHasher, Hash, Hasher}, marker::PhantomData, ops::{self, Range}, sync::atomic::{AtomicU64, Ordering as AtomicOrdering}
 };
-use twox_hash::XxHash;
 
 use super::{f64_to_u8, u64_to_f64, usize_to_f64};
-use crate::traits::{Intersect, IntersectPlusUnionIsPlus, New, UnionAssign};
+use crate::{
+	bounds::{z_score, Bounds}, error::Error, hash::XxHashBuilder, traits::{Intersect, IntersectPlusUnionIsPlus, New, UnionAssign}
+};
 
 mod consts;
-use self::consts::{BIAS_DATA, RAW_ESTIMATE_DATA, TRESHOLD_DATA};
+mod counters;
+mod datasketches;
+mod estimator;
+mod kernels;
+mod packed;
+mod postgres;
+mod redis;
+mod sliding;
+mod sparse;
+use self::{
+	consts::{BIAS_DATA, RAW_ESTIMATE_DATA, TRESHOLD_DATA}, counters::{Counters, SplitMix64}, kernels::{recount, Merge}, packed::Packed6, sparse::{Sparse, P_SPARSE}
+};
+pub use self::{
+	counters::CounterLayout, datasketches::HllType, estimator::Estimator, packed::RegisterLayout, postgres::PostgresParams, sliding::SlidingHyperLogLog
+};
+
+/// The smallest supported precision.
+const MIN_P: u8 = 4;
+/// The largest precision for which there is empirical bias correction data.
+const MAX_P_BIAS: u8 = 18;
+/// The largest supported precision. Kept below `P_SPARSE` so that every precision can start out sparse.
+const MAX_P: u8 = 24;
 
 /// Like [`HyperLogLog`] but implements `Ord` and `Eq` by using the estimate of the cardinality.
+///
+/// The estimate is cached, and only recalculated after the `HyperLogLog` has been pushed to or unioned with, so that repeated comparisons – as made by [`Top`](crate::Top) – are cheap.
 #[derive(Serialize, Deserialize)]
-#[serde(bound = "")]
-pub struct HyperLogLogMagnitude<V>(HyperLogLog<V>);
-impl<V: Hash> Ord for HyperLogLogMagnitude<V> {
+#[serde(
+	bound(serialize = "S: Serialize", deserialize = "S: Deserialize<'de>"),
+	transparent
+)]
+pub struct HyperLogLogMagnitude<V, S = XxHashBuilder>(HyperLogLog<V, S>, #[serde(skip)] CachedLen);
+impl<V: Hash, S: BuildHasher> HyperLogLogMagnitude<V, S> {
+	#[inline(always)]
+	fn len(&self) -> f64 {
+		self.1.get_or_insert_with(|| self.0.len())
+	}
+}
+impl<V: Hash, S: BuildHasher> Ord for HyperLogLogMagnitude<V, S> {
 	#[inline(always)]
 	fn cmp(&self, other: &Self) -> Ordering {
-		self.0.len().partial_cmp(&other.0.len()).unwrap()
+		self.len().partial_cmp(&other.len()).unwrap()
 	}
 }
-impl<V: Hash> PartialOrd for HyperLogLogMagnitude<V> {
+impl<V: Hash, S: BuildHasher> PartialOrd for HyperLogLogMagnitude<V, S> {
 	#[inline(always)]
 	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-		self.0.len().partial_cmp(&other.0.len())
+		self.len().partial_cmp(&other.len())
 	}
 }
-impl<V: Hash> PartialEq for HyperLogLogMagnitude<V> {
+impl<V: Hash, S: BuildHasher> PartialEq for HyperLogLogMagnitude<V, S> {
 	#[inline(always)]
 	fn eq(&self, other: &Self) -> bool {
-		self.0.len().eq(&other.0.len())
+		self.len().eq(&other.len())
 	}
 }
-impl<V: Hash> Eq for HyperLogLogMagnitude<V> {}
-impl<V: Hash> Clone for HyperLogLogMagnitude<V> {
+impl<V: Hash, S: BuildHasher> Eq for HyperLogLogMagnitude<V, S> {}
+impl<V: Hash, S: Clone> Clone for HyperLogLogMagnitude<V, S> {
 	fn clone(&self) -> Self {
-		Self(self.0.clone())
+		Self(self.0.clone(), self.1.clone())
 	}
 }
-impl<V: Hash> New for HyperLogLogMagnitude<V> {
+impl<V: Hash, S: BuildHasher + Default> New for HyperLogLogMagnitude<V, S> {
 	type Config = f64;
 	fn new(config: &Self::Config) -> Self {
-		Self(New::new(config))
+		Self(New::new(config), CachedLen::default())
 	}
 }
 
-impl<V: Hash> Intersect for HyperLogLogMagnitude<V> {
+impl<V: Hash, S: BuildHasher + Clone + PartialEq> Intersect for HyperLogLogMagnitude<V, S> {
 	fn intersect<'a>(iter: impl Iterator<Item = &'a Self>) -> Option<Self>
 	where
 		Self: Sized + 'a,
 	{
-		Intersect::intersect(iter.map(|x| &x.0)).map(Self)
+		Intersect::intersect(iter.map(|x| &x.0)).map(|x| Self(x, CachedLen::default()))
 	}
 }
-impl<'a, V: Hash> UnionAssign<&'a HyperLogLogMagnitude<V>> for HyperLogLogMagnitude<V> {
+impl<'a, V: Hash, S: BuildHasher + PartialEq> UnionAssign<&'a HyperLogLogMagnitude<V, S>>
+	for HyperLogLogMagnitude<V, S>
+{
 	fn union_assign(&mut self, rhs: &'a Self) {
-		self.0.union_assign(&rhs.0)
+		self.0.union_assign(&rhs.0);
+		self.1.clear();
 	}
 }
-impl<'a, V: Hash> ops::AddAssign<&'a V> for HyperLogLogMagnitude<V> {
+impl<'a, V: Hash, S: BuildHasher> ops::AddAssign<&'a V> for HyperLogLogMagnitude<V, S> {
 	fn add_assign(&mut self, rhs: &'a V) {
-		self.0.add_assign(rhs)
+		self.0.add_assign(rhs);
+		self.1.clear();
 	}
 }
-impl<'a, V: Hash> ops::AddAssign<&'a Self> for HyperLogLogMagnitude<V> {
+impl<'a, V: Hash, S: BuildHasher + PartialEq> ops::AddAssign<&'a Self>
+	for HyperLogLogMagnitude<V, S>
+{
 	fn add_assign(&mut self, rhs: &'a Self) {
-		self.0.add_assign(&rhs.0)
+		self.0.add_assign(&rhs.0);
+		self.1.clear();
 	}
 }
-impl<V: Hash> fmt::Debug for HyperLogLogMagnitude<V> {
+impl<V: Hash, S: BuildHasher> fmt::Debug for HyperLogLogMagnitude<V, S> {
 	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
 		self.0.fmt(fmt)
 	}
 }
-impl<V> IntersectPlusUnionIsPlus for HyperLogLogMagnitude<V> {
-	const VAL: bool = <HyperLogLog<V> as IntersectPlusUnionIsPlus>::VAL;
+impl<V, S> IntersectPlusUnionIsPlus for HyperLogLogMagnitude<V, S> {
+	const VAL: bool = <HyperLogLog<V, S> as IntersectPlusUnionIsPlus>::VAL;
+}
+
+/// The cached estimate of a [`HyperLogLogMagnitude`], as the bits of an `f64`, with NaN meaning it needs recalculating.
+///
+/// It's atomic rather than a `Cell` so that the `HyperLogLogMagnitude` stays `Sync`.
+#[derive(Debug)]
+struct CachedLen(AtomicU64);
+impl CachedLen {
+	#[inline(always)]
+	fn get_or_insert_with(&self, f: impl FnOnce() -> f64) -> f64 {
+		let len = f64::from_bits(self.0.load(AtomicOrdering::Relaxed));
+		if !len.is_nan() {
+			return len;
+		}
+		let len = f();
+		self.0.store(len.to_bits(), AtomicOrdering::Relaxed);
+		len
+	}
+
+	#[inline(always)]
+	fn clear(&mut self) {
+		*self.0.get_mut() = f64::NAN.to_bits();
+	}
+}
+impl Default for CachedLen {
+	fn default() -> Self {
+		Self(AtomicU64::new(f64::NAN.to_bits()))
+	}
+}
+impl Clone for CachedLen {
+	fn clone(&self) -> Self {
+		Self(AtomicU64::new(self.0.load(AtomicOrdering::Relaxed)))
+	}
 }
 
 /// An implementation of the [HyperLogLog](https://en.wikipedia.org/wiki/HyperLogLog) data structure with *bias correction*.
@@ -129,93 +200,281 @@ impl<V> IntersectPlusUnionIsPlus for HyperLogLogMagnitude<V> {
 /// See [*HyperLogLog: the analysis of a near-optimal cardinality estimation algorithm*](http://algo.inria.fr/flajolet/Publications/FlFuGaMe07.pdf) and [*HyperLogLog in Practice: Algorithmic Engineering of a State of The Art Cardinality Estimation Algorithm*](https://ai.google/research/pubs/pub40671) for background on HyperLogLog with bias correction.
 /// HyperLogLog support of delete operation refer to:
 /// [Every Row Counts: Combining Sketches and Sampling for Accurate Group-By Result Estimates](https://db.in.tum.de/~freitag/papers/p23-freitag-cidr19.pdf)
+///
+/// Elements are hashed with `S`, which defaults to [`XxHashBuilder`] with a seed of `0`.
+///
+/// The precision `p` (there are 2<sup>p</sup> registers) ranges from 4 to 24. How the cardinality is estimated from the registers can be chosen with [`set_estimator`](HyperLogLog::set_estimator); see [`Estimator`].
+///
+/// The dense registers take a byte each by default, or can be packed into 6 bits each with [`set_register_layout`](HyperLogLog::set_register_layout).
+///
+/// While the number of distinct elements seen is small, the registers are held in the *sparse representation* described in the HyperLogLog++ paper: a sorted list of `(index, rho)` pairs at a higher precision of 2<sup>26</sup> registers. This uses far less memory than the dense registers for low cardinalities, and is converted to the dense registers once it would be larger than them.
+///
+/// The serialized form holds whichever representation the registers are in, so it isn't compatible with that of version 0.3 and earlier, which always held the dense registers: those `HyperLogLog`s fail to deserialize.
 #[derive(Serialize, Deserialize)]
-#[serde(bound = "")]
-pub struct HyperLogLog<V: ?Sized> {
+#[serde(
+	bound(serialize = "S: Serialize", deserialize = "S: Deserialize<'de>"),
+	try_from = "HyperLogLogUnchecked<V, S>"
+)]
+pub struct HyperLogLog<V: ?Sized, S = XxHashBuilder> {
 	alpha: f64,
 	zero: usize,
 	sum: f64,
 	p: u8,
-	m: Box<[u8]>,
-	counters: Option<Vec<Box<[u8]>>>,
+	m: Registers,
+	counters: Option<Counters>,
+	rng: SplitMix64,
+	estimator: Estimator,
+	layout: RegisterLayout,
+	hasher: S,
 	marker: PhantomData<fn(V)>,
 }
 
+/// A deserialized [`HyperLogLog`] that hasn't yet been checked to be valid.
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "S: Deserialize<'de>"))]
+struct HyperLogLogUnchecked<V: ?Sized, S> {
+	alpha: f64,
+	zero: usize,
+	sum: f64,
+	p: u8,
+	m: Registers,
+	counters: Option<Counters>,
+	rng: SplitMix64,
+	estimator: Estimator,
+	layout: RegisterLayout,
+	hasher: S,
+	marker: PhantomData<fn(V)>,
+}
+impl<V: ?Sized, S> TryFrom<HyperLogLogUnchecked<V, S>> for HyperLogLog<V, S> {
+	type Error = Error;
+
+	fn try_from(hll: HyperLogLogUnchecked<V, S>) -> Result<Self, Error> {
+		let HyperLogLogUnchecked {
+			alpha,
+			p,
+			m,
+			counters,
+			rng,
+			estimator,
+			layout,
+			hasher,
+			..
+		} = hll;
+		if !(MIN_P..=MAX_P).contains(&p) {
+			return Err(Error::Deserialization("HyperLogLog precision out of range"));
+		}
+		let (zero, sum) = match &m {
+			Registers::Sparse(sparse) => {
+				if !sparse.is_valid() {
+					return Err(Error::Deserialization(
+						"HyperLogLog sparse registers are malformed",
+					));
+				}
+				(1 << p, f64::from(1 << p))
+			}
+			Registers::Dense(m) => {
+				if m.len() != 1 << p || m.iter().any(|&x| x > 65 - p) {
+					return Err(Error::Deserialization(
+						"HyperLogLog registers are malformed",
+					));
+				}
+				recount(m)
+			}
+			Registers::Packed(packed) => {
+				// check the length before unpacking, which reads `len` registers from the bytes
+				if packed.len() != 1 << p || !packed.is_valid() {
+					return Err(Error::Deserialization(
+						"HyperLogLog registers are malformed",
+					));
+				}
+				let m = packed.to_registers();
+				if m.iter().any(|&x| x > 65 - p) {
+					return Err(Error::Deserialization(
+						"HyperLogLog registers are malformed",
+					));
+				}
+				recount(&m)
+			}
+		};
+		// the dense registers themselves say which layout they're in
+		let layout = m.layout().unwrap_or(layout);
+		if let Some(counters) = &counters {
+			if matches!(m, Registers::Sparse(_)) || !counters.is_valid(p) {
+				return Err(Error::Deserialization("HyperLogLog counters are malformed"));
+			}
+		}
+		Ok(Self {
+			alpha,
+			zero,
+			sum,
+			p,
+			m,
+			counters,
+			rng,
+			estimator,
+			layout,
+			hasher,
+			marker: PhantomData,
+		})
+	}
+}
+
 impl<V: ?Sized> HyperLogLog<V>
 where
 	V: Hash,
 {
 	/// Create an empty `HyperLogLog` data structure with the specified error tolerance.
 	pub fn new(error_rate: f64) -> Self {
-		assert!(0.0 < error_rate && error_rate < 1.0);
-		let p = f64_to_u8((f64::log2(1.04 / error_rate) * 2.0).ceil());
-		assert!(0 < p && p < 64);
+		Self::with_hasher(error_rate, XxHashBuilder::default())
+	}
+
+	/// Create an empty `HyperLogLog` data structure with the specified error tolerance, or return an error if it's invalid.
+	pub fn try_new(error_rate: f64) -> Result<Self, Error> {
+		Self::try_with_hasher(error_rate, XxHashBuilder::default())
+	}
+
+	/// Create an empty `HyperLogLog` data structure with the specified error tolerance.
+	/// Also create a counters to support delete operation.
+	pub fn new_with_counters(error_rate: f64) -> Self {
+		Self::with_counters_and_hasher(error_rate, XxHashBuilder::default())
+	}
+
+	/// Like [`new_with_counters`](Self::new_with_counters), but returns an error if the error tolerance is invalid.
+	pub fn try_new_with_counters(error_rate: f64) -> Result<Self, Error> {
+		Self::try_with_counters_and_hasher(error_rate, XxHashBuilder::default())
+	}
+
+	/// Create an empty `HyperLogLog` data structure with 2<sup>`p`</sup> registers.
+	pub fn with_precision(p: u8) -> Self {
+		Self::with_precision_and_hasher(p, XxHashBuilder::default())
+	}
+
+	/// Like [`with_precision`](Self::with_precision), but returns an error if `p` is out of range.
+	pub fn try_with_precision(p: u8) -> Result<Self, Error> {
+		Self::try_with_precision_and_hasher(p, XxHashBuilder::default())
+	}
+}
+
+impl<V: ?Sized, S> HyperLogLog<V, S>
+where
+	V: Hash,
+	S: BuildHasher,
+{
+	/// Create an empty `HyperLogLog` data structure with the specified error tolerance, that hashes elements with `hasher`.
+	pub fn with_hasher(error_rate: f64, hasher: S) -> Self {
+		Self::try_with_hasher(error_rate, hasher).unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`with_hasher`](Self::with_hasher), but returns an error if the error tolerance is invalid.
+	pub fn try_with_hasher(error_rate: f64, hasher: S) -> Result<Self, Error> {
+		let p = Self::get_p(error_rate)?;
+		Self::try_with_precision_and_hasher(p, hasher)
+	}
+
+	/// Create an empty `HyperLogLog` data structure with 2<sup>`p`</sup> registers, that hashes elements with `hasher`.
+	pub fn with_precision_and_hasher(p: u8, hasher: S) -> Self {
+		Self::try_with_precision_and_hasher(p, hasher).unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`with_precision_and_hasher`](Self::with_precision_and_hasher), but returns an error if `p` is out of range.
+	pub fn try_with_precision_and_hasher(p: u8, hasher: S) -> Result<Self, Error> {
+		Self::check_p(p)?;
 		let alpha = Self::get_alpha(p);
-		Self {
+		Ok(Self {
 			alpha,
 			zero: 1 << p,
 			sum: f64::from(1 << p),
 			p,
-			m: vec![0; 1 << p].into_boxed_slice(),
+			m: Registers::new(p, RegisterLayout::default()),
 			counters: None,
+			rng: SplitMix64::default(),
+			estimator: Estimator::default(),
+			layout: RegisterLayout::default(),
+			hasher,
 			marker: PhantomData,
-		}
+		})
 	}
 
-	/// Create an empty `HyperLogLog` data structure with the specified error tolerance.
+	/// Create an empty `HyperLogLog` data structure with the specified error tolerance, that hashes elements with `hasher`.
 	/// Also create a counters to support delete operation.
-	pub fn new_with_counters(error_rate: f64) -> Self {
-		assert!(0.0 < error_rate && error_rate < 1.0);
-		let p = f64_to_u8((f64::log2(1.04 / error_rate) * 2.0).ceil());
-		assert!(0 < p && p < 64);
+	pub fn with_counters_and_hasher(error_rate: f64, hasher: S) -> Self {
+		Self::try_with_counters_and_hasher(error_rate, hasher)
+			.unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`with_counters_and_hasher`](Self::with_counters_and_hasher), but returns an error if the error tolerance is invalid.
+	pub fn try_with_counters_and_hasher(error_rate: f64, hasher: S) -> Result<Self, Error> {
+		let p = Self::get_p(error_rate)?;
 		let alpha = Self::get_alpha(p);
-		let max_width = 64 - p as usize;
-		Self {
+		Ok(Self {
 			alpha,
 			zero: 1 << p,
 			sum: f64::from(1 << p),
 			p,
-			m: vec![0; 1 << p].into_boxed_slice(),
-			counters: Some(vec![vec![0; max_width].into_boxed_slice(); 1 << p]),
+			m: Registers::dense(p, RegisterLayout::default()),
+			counters: Some(Counters::new(p, CounterLayout::default())),
+			rng: SplitMix64::default(),
+			estimator: Estimator::default(),
+			layout: RegisterLayout::default(),
+			hasher,
 			marker: PhantomData,
-		}
+		})
 	}
 
 	/// Create an empty `HyperLogLog` data structure, copying the error tolerance from `hll`.
-	pub fn new_from(hll: &Self) -> Self {
+	pub fn new_from(hll: &Self) -> Self
+	where
+		S: Clone,
+	{
 		Self {
 			alpha: hll.alpha,
-			zero: hll.m.len(),
-			sum: usize_to_f64(hll.m.len()),
+			zero: 1 << hll.p,
+			sum: f64::from(1 << hll.p),
 			p: hll.p,
-			m: vec![0; hll.m.len()].into_boxed_slice(),
+			m: if hll.counters.is_none() {
+				Registers::new(hll.p, hll.layout)
+			} else {
+				Registers::dense(hll.p, hll.layout)
+			},
 			counters: hll.counters.clone(),
+			rng: hll.rng,
+			estimator: hll.estimator,
+			layout: hll.layout,
+			hasher: hll.hasher.clone(),
 			marker: PhantomData,
 		}
 	}
 
 	#[inline]
-	fn is_change_power(power: u8) -> bool {
+	fn is_change_power(rng: &mut SplitMix64, power: u8) -> bool {
 		assert!(power >= 1);
 		let p = u64::from(power);
 		if p >= u64::BITS as u64 {
 			return false;
 		}
-		random::<u64>() % (2 << (p - 1)) == 0
+		rng.next_u64() % (2 << (p - 1)) == 0
 	}
 
 	/// "Visit" an element.
 	#[inline]
 	pub fn push(&mut self, value: &V) {
-		let mut hasher = XxHash::default();
-		value.hash(&mut hasher);
-		let x = hasher.finish();
-		let j = x & (self.m.len() as u64 - 1);
+		self.push_hash(self.hasher.hash_one(value));
+	}
+
+	#[inline]
+	fn push_hash(&mut self, x: u64) {
+		if let Registers::Sparse(sparse) = &mut self.m {
+			sparse.push(x);
+			if sparse.len() > Sparse::max_len(self.p) {
+				self.densify();
+			}
+			return;
+		}
+		let j = x & ((1 << self.p) - 1);
 		let index = usize::try_from(j).unwrap();
 		let w = x >> self.p;
-		let rho = Self::get_rho(w, 64 - self.p);
-		let mjr = &mut self.m[index];
-		let old = *mjr;
+		let rho = get_rho(w, 64 - self.p);
+		let old = self.m.get(index);
 		let new = cmp::max(old, rho);
 		self.zero -= if old == 0 { 1 } else { 0 };
 
@@ -225,18 +484,70 @@ where
 
 		// update counters
 		if let Some(counters) = &mut self.counters {
-			let c = &mut counters[index];
-			let now_counter = &mut c[new as usize];
+			let now_counter = &mut counters.row_mut(index)[usize::from(new)];
 			if *now_counter <= 128 {
 				*now_counter += 1;
 			} else {
-				if Self::is_change_power(*now_counter - 128) {
+				if Self::is_change_power(&mut self.rng, *now_counter - 128) {
 					*now_counter += 1;
 				}
 			}
 		}
 
-		*mjr = new;
+		self.m.set(index, new);
+	}
+
+	/// "Visit" many elements at once.
+	///
+	/// This is equivalent to calling [`push`](Self::push) for each of `values`, but amortizes the bookkeeping across the batch.
+	pub fn push_batch(&mut self, values: &[V])
+	where
+		V: Sized,
+	{
+		let mut batch = [0; 256];
+		for chunk in values.chunks(batch.len()) {
+			for (x, value) in batch.iter_mut().zip(chunk) {
+				*x = self.hasher.hash_one(value);
+			}
+			self.push_hashes(&batch[..chunk.len()]);
+		}
+	}
+
+	/// "Visit" many elements at once, given their hashes.
+	///
+	/// The hashes should be uniformly distributed over `u64`, and to mix with elements added with [`push`](Self::push) they must be the result of hashing with the `HyperLogLog`'s hasher. Once the registers are dense, the running estimate is updated once for the whole batch.
+	pub fn push_hashes(&mut self, hashes: &[u64]) {
+		let mut hashes = hashes;
+		while let Registers::Sparse(sparse) = &mut self.m {
+			let Some((&x, rest)) = hashes.split_first() else {
+				return;
+			};
+			hashes = rest;
+			sparse.push(x);
+			if sparse.len() > Sparse::max_len(self.p) {
+				self.densify();
+			}
+		}
+		if self.counters.is_some() {
+			for &x in hashes {
+				self.push_hash(x);
+			}
+			return;
+		}
+		let (mut zero, mut sum) = (self.zero, self.sum);
+		let m = &mut self.m;
+		for_each_register(hashes, self.p, |index, rho| {
+			let old = m.get(index);
+			if rho > old {
+				zero -= usize::from(old == 0);
+				// see pow_bithack()
+				sum -= f64::from_bits(u64::MAX.wrapping_sub(u64::from(old)) << 54 >> 2)
+					- f64::from_bits(u64::MAX.wrapping_sub(u64::from(rho)) << 54 >> 2);
+				m.set(index, rho);
+			}
+		});
+		self.zero = zero;
+		self.sum = sum;
 	}
 
 	/// "Remove" an element.
@@ -244,20 +555,21 @@ where
 	pub fn delete(&mut self, value: &V) {
 		let max_width = 64 - self.p;
 		if let Some(counters) = &mut self.counters {
-			let mut hasher = XxHash::default();
-			value.hash(&mut hasher);
-			let x = hasher.finish();
-			let j = x & (self.m.len() as u64 - 1);
+			let x = self.hasher.hash_one(value);
+			let j = x & ((1 << self.p) - 1);
 			let index = usize::try_from(j).unwrap();
 			let w = x >> self.p;
-			let rho = Self::get_rho(w, max_width);
-			let c = &mut counters[index];
-			let old_counter = &mut c[rho as usize];
+			let rho = get_rho(w, max_width);
+			let c = match counters.row(index) {
+				Some(_) => counters.row_mut(index),
+				None => return,
+			};
+			let old_counter = &mut c[usize::from(rho)];
 			if *old_counter >= 1 {
 				if *old_counter <= 128 {
 					*old_counter -= 1;
 				} else {
-					if Self::is_change_power(*old_counter - 128) {
+					if Self::is_change_power(&mut self.rng, *old_counter - 128) {
 						*old_counter -= 1;
 					}
 				}
@@ -269,7 +581,6 @@ where
 					self.sum -=
 						f64::from_bits(u64::max_value().wrapping_sub(u64::from(rho)) << 54 >> 2);
 					// Find the biggest value less than rho
-					let mjr = &mut self.m[index];
 					for i in (0..rho - 1).rev() {
 						if c[i as usize] > 0 {
 							self.zero -= if i != 0 { 1 } else { 0 };
@@ -277,11 +588,11 @@ where
 							self.sum += f64::from_bits(
 								u64::max_value().wrapping_sub(u64::from(i)) << 54 >> 2,
 							);
-							*mjr = i;
+							self.m.set(index, i);
 							return;
 						}
 					}
-					*mjr = 0;
+					self.m.set(index, 0);
 				}
 			}
 		} else {
@@ -291,12 +602,68 @@ where
 		}
 	}
 
-	/// Retrieve an estimate of the carginality of the stream.
+	/// Retrieve an estimate of the carginality of the stream, using the [`Estimator`] set with [`set_estimator`](Self::set_estimator).
 	pub fn len(&self) -> f64 {
+		match self.estimator {
+			Estimator::HyperLogLogPlusPlus if self.p <= MAX_P_BIAS => self.len_plus_plus(),
+			Estimator::HyperLogLogPlusPlus | Estimator::Improved => {
+				estimator::improved(&self.histogram())
+			}
+			Estimator::MaximumLikelihood => estimator::maximum_likelihood(&self.histogram()),
+		}
+	}
+
+	/// Retrieve an estimate of the cardinality of the stream, together with bounds that it lies within with probability `confidence`.
+	///
+	/// The bounds are derived from the relative standard error `1.04/sqrt(m)` of the `m = 2^p` registers, assuming the error is normally distributed. This is pessimistic while the `HyperLogLog` is sparse.
+	pub fn len_with_bounds(&self, confidence: f64) -> Bounds {
+		self.try_len_with_bounds(confidence)
+			.unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`len_with_bounds`](Self::len_with_bounds), but returns an error if `confidence` isn't between 0 and 1.
+	pub fn try_len_with_bounds(&self, confidence: f64) -> Result<Bounds, Error> {
+		let margin = z_score(confidence)? * 1.04 / f64::from(1 << self.p).sqrt();
+		let estimate = self.len();
+		Ok(Bounds {
+			lower: (estimate * (1.0 - margin)).max(0.0),
+			estimate,
+			upper: estimate * (1.0 + margin),
+		})
+	}
+
+	/// The estimator used by [`len`](Self::len).
+	pub fn estimator(&self) -> Estimator {
+		self.estimator
+	}
+
+	/// Set the estimator used by [`len`](Self::len).
+	pub fn set_estimator(&mut self, estimator: Estimator) {
+		self.estimator = estimator;
+	}
+
+	/// The number of registers with each value `0..=65-p`, or for the sparse representation the number of registers at its higher precision with each value `0..=65-P_SPARSE`.
+	fn histogram(&self) -> Vec<usize> {
+		match &self.m {
+			Registers::Sparse(sparse) => sparse.histogram(),
+			m => {
+				let mut c = vec![0; usize::from(66 - self.p)];
+				for &x in m.unpacked().iter() {
+					c[usize::from(x)] += 1;
+				}
+				c
+			}
+		}
+	}
+
+	fn len_plus_plus(&self) -> f64 {
+		if let Registers::Sparse(sparse) = &self.m {
+			return sparse.len_estimate();
+		}
 		let v = self.zero;
 		if v > 0 {
-			let h =
-				usize_to_f64(self.m.len()) * (usize_to_f64(self.m.len()) / usize_to_f64(v)).ln();
+			let m = f64::from(1 << self.p);
+			let h = m * (m / usize_to_f64(v)).ln();
 			if h <= Self::get_threshold(self.p - 4) {
 				return h;
 			}
@@ -304,82 +671,139 @@ where
 		self.ep()
 	}
 
+	/// The precision `p` of this `HyperLogLog`, i.e. it has 2<sup>p</sup> registers.
+	pub fn precision(&self) -> u8 {
+		self.p
+	}
+
 	/// Returns true if empty.
 	pub fn is_empty(&self) -> bool {
-		self.zero == self.m.len()
+		match &self.m {
+			Registers::Sparse(sparse) => sparse.is_empty(),
+			Registers::Dense(_) | Registers::Packed(_) => self.zero == 1 << self.p,
+		}
+	}
+
+	/// A hash of the precision, registers and deletion counters, that's consistent with `PartialEq` and stable across processes and platforms.
+	///
+	/// This is suitable for deduplicating sketches, or keying caches with them. It doesn't depend on the `HyperLogLog`'s hasher.
+	pub fn fingerprint(&self) -> u64 {
+		let mut hasher = XxHashBuilder::default().build_hasher();
+		self.hash_contents(&mut hasher);
+		hasher.finish()
+	}
+
+	/// Feed what `PartialEq` compares to `state`, independently of the layout and as little-endian bytes.
+	fn hash_contents<H: Hasher>(&self, state: &mut H) {
+		state.write(&[self.p]);
+		match &self.m {
+			_ if self.is_empty() => state.write(&[0]),
+			Registers::Sparse(sparse) => {
+				state.write(&[1]);
+				for (index, rho) in sparse.iter() {
+					state.write(&index.to_le_bytes());
+					state.write(&[rho]);
+				}
+			}
+			m => {
+				state.write(&[2]);
+				state.write(&m.unpacked());
+			}
+		}
+		if let Some(counters) = &self.counters {
+			let zeros = vec![0; usize::from(66 - self.p)];
+			state.write(&[1]);
+			for register in 0..counters.len() {
+				state.write(counters.row(register).unwrap_or(&zeros));
+			}
+		}
 	}
 
 	/// Merge another HyperLogLog data structure into `self`.
 	///
 	/// This is the same as an HLL approximating cardinality of the union of two multisets.
-	pub fn union(&mut self, src: &Self) {
-		assert_eq!(src.alpha, self.alpha);
-		assert_eq!(src.p, self.p);
-		assert_eq!(src.m.len(), self.m.len());
-		#[cfg(all(
-			feature = "packed_simd",
-			any(target_arch = "x86", target_arch = "x86_64")
-		))]
+	///
+	/// Both must have been created with the same hasher. If `src` has a different precision, the result has the lower of the two: the registers of the more precise one are folded down as in [`reduce_precision`](Self::reduce_precision).
+	pub fn union(&mut self, src: &Self)
+	where
+		S: PartialEq,
+	{
+		self.try_union(src).unwrap_or_else(|err| panic!("{}", err));
+	}
+
+	/// Like [`union`](Self::union), but returns an error if `src` can't be merged into `self`.
+	pub fn try_union(&mut self, src: &Self) -> Result<(), Error>
+	where
+		S: PartialEq,
+	{
+		if self.hasher != src.hasher {
+			return Err(Error::Incompatible(
+				"HyperLogLogs must have the same hasher to be merged",
+			));
+		}
+		if src.p != self.p && (self.counters.is_some() || src.counters.is_some()) {
+			return Err(Error::Incompatible(
+				"HyperLogLogs with counters must have the same precision",
+			));
+		}
+		if self.counters.is_some() && src.counters.is_none() {
+			return Err(Error::Incompatible(
+				"HyperLogLog without counters can't be merged into one with counters",
+			));
+		}
+		if self.p > src.p {
+			self.try_reduce_precision(src.p)?;
+		}
+		if let (Registers::Sparse(self_sparse), Registers::Sparse(src_sparse)) =
+			(&mut self.m, &src.m)
 		{
-			assert_eq!(self.m.len() % u8s::lanes(), 0); // TODO: high error rate can trigger this
-			assert_eq!(u8s::lanes(), f32s::lanes() * 4);
-			assert_eq!(f32s::lanes(), u32s::lanes());
-			assert_eq!(u8sq::lanes(), u32s::lanes());
-			let mut zero = u8s_sad_out::splat(0);
-			let mut sum = f32s::splat(0.0);
-			for i in (0..self.m.len()).step_by(u8s::lanes()) {
-				unsafe {
-					let self_m = u8s::from_slice_unaligned_unchecked(self.m.get_unchecked(i..));
-					let src_m = u8s::from_slice_unaligned_unchecked(src.m.get_unchecked(i..));
-					let res = self_m.max(src_m);
-					res.write_to_slice_unaligned_unchecked(self.m.get_unchecked_mut(i..));
-					let count: u8s = u8s::splat(0) - u8s::from_bits(res.eq(u8s::splat(0)));
-					let count2 = Sad::<u8s>::sad(count, u8s::splat(0));
-					zero += count2;
-					for j in 0..4 {
-						let x = u8sq::from_slice_unaligned_unchecked(
-							self.m.get_unchecked(i + j * u8sq::lanes()..),
-						);
-						let x: u32s = x.cast();
-						let x: f32s = ((u32s::splat(u32::max_value()) - x) << 25 >> 2).into_bits();
-						sum += x;
-					}
-				}
+			self_sparse.union(src_sparse);
+			if self_sparse.len() > Sparse::max_len(self.p) {
+				self.densify();
 			}
-			self.zero = usize::try_from(zero.wrapping_sum()).unwrap();
-			self.sum = f64::from(sum.sum());
-			// https://github.com/AdamNiederer/faster/issues/37
-			// (src.m.simd_iter(faster::u8s(0)),self.m.simd_iter_mut(faster::u8s(0))).zip()
+			return Ok(());
 		}
-		#[cfg(not(all(
-			feature = "packed_simd",
-			any(target_arch = "x86", target_arch = "x86_64")
-		)))]
-		{
-			let mut zero = 0;
-			let mut sum = 0.0;
-			for (to, from) in self.m.iter_mut().zip(src.m.iter()) {
-				*to = (*to).max(*from);
-				zero += if *to == 0 { 1 } else { 0 };
-				sum += f64::from_bits(u64::max_value().wrapping_sub(u64::from(*to)) << 54 >> 2);
+		self.densify();
+		let src_m = match &src.m {
+			Registers::Sparse(src_sparse) => {
+				let mut m = self.m.unpacked().into_owned();
+				src_sparse.fold_into(&mut m, self.p);
+				let (zero, sum) = recount(&m);
+				self.zero = zero;
+				self.sum = sum;
+				self.m = Registers::from_unpacked(m.into_boxed_slice(), self.layout);
+				return Ok(());
 			}
+			src_m if src.p > self.p => {
+				Cow::Owned(fold_dense(&src_m.unpacked(), src.p, self.p).into())
+			}
+			src_m => src_m.unpacked(),
+		};
+		if let Registers::Packed(m) = &mut self.m {
+			let (zero, sum) = merge_packed(m, &src_m, cmp::max);
 			self.zero = zero;
 			self.sum = sum;
+		} else {
+			self.union_dense(&src_m);
 		}
 
 		if let Some(counters) = &mut self.counters {
-			let max_width = 64 - self.p;
+			let src_counters = src.counters.as_ref().unwrap();
+			let zeros = vec![0; usize::from(66 - self.p)];
 			for i in 0..1 << self.p {
-				let to = &mut counters[i];
-				let from = &src.counters.as_ref().unwrap()[i];
+				let from = src_counters.row(i).unwrap_or(&zeros);
+				if counters.row(i).is_none() && from == &*zeros {
+					continue;
+				}
+				let to = counters.row_mut(i);
 				// From max to 0, merge the max counter
-				for j in (0..max_width).rev() {
-					let idx = j as usize;
-					let to_counter = &mut to[idx];
-					let from_counter = &from[idx];
+				for j in (0..to.len()).rev() {
+					let to_counter = &mut to[j];
+					let from_counter = &from[j];
 					if *to_counter > 0 || *from_counter > 0 {
 						if *to_counter as u16 + *from_counter as u16 > 128 {
 							if Self::is_change_power(
+								&mut self.rng,
 								(*to_counter as u16 + *from_counter as u16 - 128) as u8,
 							) {
 								*to_counter += 1;
@@ -392,103 +816,469 @@ where
 				}
 			}
 		}
+		Ok(())
+	}
+
+	/// Intersect another HyperLogLog data structure into `self`.
+	///
+	/// Note: This is different to an HLL approximating cardinality of the intersection of two multisets; for that estimate see [`intersection_len`](Self::intersection_len).
+	pub fn intersect(&mut self, src: &Self)
+	where
+		S: PartialEq,
+	{
+		self.try_intersect(src)
+			.unwrap_or_else(|err| panic!("{}", err));
+	}
+
+	/// Like [`intersect`](Self::intersect), but returns an error if `src` can't be intersected with `self`.
+	pub fn try_intersect(&mut self, src: &Self) -> Result<(), Error>
+	where
+		S: PartialEq,
+	{
+		if self.hasher != src.hasher {
+			return Err(Error::Incompatible(
+				"HyperLogLogs must have the same hasher to be intersected",
+			));
+		}
+		if src.p != self.p {
+			return Err(Error::Incompatible(
+				"HyperLogLogs have different precisions",
+			));
+		}
+		if src.counters.is_some() != self.counters.is_some() {
+			return Err(Error::Incompatible(
+				"HyperLogLogs must both or neither have counters",
+			));
+		}
+		self.densify();
+		let src_m: Cow<'_, [u8]> = match &src.m {
+			Registers::Sparse(src_sparse) => Cow::Owned(src_sparse.to_dense(self.p).into()),
+			src_m => src_m.unpacked(),
+		};
+		if let Registers::Packed(m) = &mut self.m {
+			let (zero, sum) = merge_packed(m, &src_m, cmp::min);
+			self.zero = zero;
+			self.sum = sum;
+		} else {
+			self.intersect_dense(&src_m);
+		}
+
+		if let Some(counters) = &mut self.counters {
+			let src_counters = src.counters.as_ref().unwrap();
+			let zeros = vec![0; usize::from(66 - self.p)];
+			for i in 0..1 << self.p {
+				let from = src_counters.row(i).unwrap_or(&zeros);
+				if counters.row(i).is_none() && from == &*zeros {
+					continue;
+				}
+				let to = counters.row_mut(i);
+				// From 0 to max, merge the min counter
+				for j in 0..to.len() {
+					let from_counter = &from[j];
+					let to_counter = &mut to[j];
+					if *to_counter > 0 || *from_counter > 0 {
+						if *to_counter as u16 + *from_counter as u16 > 128 {
+							if Self::is_change_power(
+								&mut self.rng,
+								(*to_counter as u16 + *from_counter as u16 - 128) as u8,
+							) {
+								*to_counter += 1;
+							}
+						}
+						break;
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Merge the dense registers `src_m` into the dense byte-per-register registers of `self`, taking the maximum of each.
+	fn union_dense(&mut self, src_m: &[u8]) {
+		let (zero, sum) = kernels::merge(self.m.dense_mut(), src_m, Merge::Max);
+		self.zero = zero;
+		self.sum = sum;
+	}
+
+	/// Merge the dense registers `src_m` into the dense byte-per-register registers of `self`, taking the minimum of each.
+	fn intersect_dense(&mut self, src_m: &[u8]) {
+		let (zero, sum) = kernels::merge(self.m.dense_mut(), src_m, Merge::Min);
+		self.zero = zero;
+		self.sum = sum;
+	}
+
+	/// Reduce the precision of `self` to `p`, so there are 2<sup>`p`</sup> registers, by folding together the registers that share the low `p` bits of their index.
+	///
+	/// The result is identical to a `HyperLogLog` of precision `p` that had been pushed the same elements, so this is useful to compact sketches for archival or to merge them with less precise ones.
+	pub fn reduce_precision(&mut self, p: u8) {
+		self.try_reduce_precision(p)
+			.unwrap_or_else(|err| panic!("{}", err));
+	}
+
+	/// Like [`reduce_precision`](Self::reduce_precision), but returns an error if `p` is greater than the current precision or out of range, or if `self` has counters.
+	pub fn try_reduce_precision(&mut self, p: u8) -> Result<(), Error> {
+		Self::check_p(p)?;
+		if p > self.p {
+			return Err(Error::InvalidParameter("precision can only be reduced"));
+		}
+		if self.counters.is_some() {
+			return Err(Error::InvalidParameter(
+				"precision of a HyperLogLog with counters can't be reduced",
+			));
+		}
+		if p == self.p {
+			return Ok(());
+		}
+		let from = self.p;
+		self.p = p;
+		self.alpha = Self::get_alpha(p);
+		match &mut self.m {
+			Registers::Sparse(sparse) => {
+				// the sparse representation is independent of p
+				self.zero = 1 << p;
+				self.sum = f64::from(1 << p);
+				if sparse.len() > Sparse::max_len(p) {
+					self.densify();
+				}
+			}
+			m => {
+				let m = fold_dense(&m.unpacked(), from, p);
+				let (zero, sum) = recount(&m);
+				self.zero = zero;
+				self.sum = sum;
+				self.m = Registers::from_unpacked(m, self.layout);
+			}
+		}
+		Ok(())
+	}
+
+	/// Estimate the number of distinct elements that were pushed to both `self` and `other`.
+	///
+	/// This uses the inclusion–exclusion principle, `|A ∩ B| = |A| + |B| - |A ∪ B|`, so its absolute error is that of the larger of the three estimates. It is therefore only useful if the intersection isn't much smaller than the union.
+	///
+	/// If the precisions differ, the union is estimated at the lower of the two, as [`union`](Self::union) folds the more precise registers down.
+	pub fn intersection_len(&self, other: &Self) -> f64
+	where
+		S: Clone + PartialEq,
+	{
+		self.try_intersection_len(other)
+			.unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`intersection_len`](Self::intersection_len), but returns an error if `other` can't be merged into `self`, as for [`try_union`](Self::try_union).
+	pub fn try_intersection_len(&self, other: &Self) -> Result<f64, Error>
+	where
+		S: Clone + PartialEq,
+	{
+		let (a, b) = (self.len(), other.len());
+		let union = self.union_len(other)?;
+		Ok((a + b - union).max(0.0).min(a.min(b)))
+	}
+
+	/// Estimate the number of distinct elements that were pushed to `self` but not to `other`.
+	///
+	/// This uses the inclusion–exclusion principle, `|A \ B| = |A ∪ B| - |B|`, and so has the same caveats as [`intersection_len`](Self::intersection_len).
+	pub fn difference_len(&self, other: &Self) -> f64
+	where
+		S: Clone + PartialEq,
+	{
+		self.try_difference_len(other)
+			.unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`difference_len`](Self::difference_len), but returns an error if `other` can't be merged into `self`, as for [`try_union`](Self::try_union).
+	pub fn try_difference_len(&self, other: &Self) -> Result<f64, Error>
+	where
+		S: Clone + PartialEq,
+	{
+		let (a, b) = (self.len(), other.len());
+		let union = self.union_len(other)?;
+		Ok((union - b).max(0.0).min(a))
+	}
+
+	/// Estimate the [Jaccard index](https://en.wikipedia.org/wiki/Jaccard_index) `|A ∩ B| / |A ∪ B|` of the distinct elements pushed to `self` and `other`.
+	///
+	/// The intersection is estimated as in [`intersection_len`](Self::intersection_len). Two empty `HyperLogLog`s have a Jaccard index of `0`.
+	pub fn jaccard(&self, other: &Self) -> f64
+	where
+		S: Clone + PartialEq,
+	{
+		self.try_jaccard(other)
+			.unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`jaccard`](Self::jaccard), but returns an error if `other` can't be merged into `self`, as for [`try_union`](Self::try_union).
+	pub fn try_jaccard(&self, other: &Self) -> Result<f64, Error>
+	where
+		S: Clone + PartialEq,
+	{
+		let (a, b) = (self.len(), other.len());
+		let union = self.union_len(other)?;
+		if union == 0.0 {
+			return Ok(0.0);
+		}
+		let intersection = (a + b - union).max(0.0).min(a.min(b));
+		Ok((intersection / union).min(1.0))
+	}
+
+	/// Estimate the cardinality of the union of `self` and `other`, without modifying either.
+	fn union_len(&self, other: &Self) -> Result<f64, Error>
+	where
+		S: Clone + PartialEq,
+	{
+		let mut union = Self {
+			alpha: self.alpha,
+			zero: self.zero,
+			sum: self.sum,
+			p: self.p,
+			m: self.m.clone(),
+			counters: None,
+			rng: SplitMix64::default(),
+			estimator: self.estimator,
+			layout: self.layout,
+			hasher: self.hasher.clone(),
+			marker: PhantomData,
+		};
+		union.try_union(other)?;
+		Ok(union.len())
+	}
+
+	/// Clears the `HyperLogLog` data structure, as if it was new.
+	pub fn clear(&mut self) {
+		self.zero = 1 << self.p;
+		self.sum = f64::from(1 << self.p);
+		if self.counters.is_none() {
+			self.m = Registers::new(self.p, self.layout);
+		} else {
+			self.m = Registers::dense(self.p, self.layout);
+		}
+		if let Some(counters) = &mut self.counters {
+			counters.clear();
+		}
+	}
+
+	/// Convert from the sparse representation to the dense registers, if not already.
+	fn densify(&mut self) {
+		if let Registers::Sparse(sparse) = &self.m {
+			let m = sparse.to_dense(self.p);
+			let (zero, sum) = recount(&m);
+			self.zero = zero;
+			self.sum = sum;
+			self.m = Registers::from_unpacked(m, self.layout);
+		}
+	}
+
+	/// The layout of the dense registers.
+	pub fn register_layout(&self) -> RegisterLayout {
+		self.layout
+	}
+
+	/// The layout of the counters, or `None` if `self` was created without counters.
+	pub fn counter_layout(&self) -> Option<CounterLayout> {
+		self.counters.as_ref().map(Counters::layout)
+	}
+
+	/// Seed the random number generator used for the probabilistic increments and decrements of counters above 128.
+	///
+	/// The generator state is part of `self`, including when serialized, so pushing and deleting the same elements in the same order on `HyperLogLog`s with the same seed results in identical counters. The default seed is `0`.
+	pub fn set_counter_seed(&mut self, seed: u64) {
+		self.rng = SplitMix64::with_seed(seed);
+	}
+
+	/// Set the layout of the counters, converting them. This has no effect if `self` was created without counters.
+	pub fn set_counter_layout(&mut self, layout: CounterLayout) {
+		if let Some(counters) = &mut self.counters {
+			counters.set_layout(layout);
+		}
+	}
+
+	/// Set the layout of the dense registers, converting them if `self` is already dense.
+	pub fn set_register_layout(&mut self, layout: RegisterLayout) {
+		self.layout = layout;
+		if matches!(self.m.layout(), Some(current) if current != layout) {
+			let m = self.m.unpacked().into_owned();
+			self.m = Registers::from_unpacked(m.into_boxed_slice(), layout);
+		}
+	}
+
+	/// "Visit" an element hashed as Redis's `PFADD` hashes it, with MurmurHash64A, bypassing the hasher.
+	///
+	/// Elements pushed this way land in the same registers as in Redis, so the result of [`to_redis_bytes`](Self::to_redis_bytes) can be merged with HyperLogLogs built by Redis itself. This needs a precision of 14 to be meaningful.
+	pub fn push_redis(&mut self, element: &[u8]) {
+		self.push_hash(redis::hash(element));
+	}
+
+	/// Serialize to the string Redis stores for a HyperLogLog, in its sparse encoding if that fits within Redis's default `hll-sparse-max-bytes`, and its dense encoding otherwise.
+	///
+	/// Redis HyperLogLogs always have a precision of 14, so this returns [`Error::Incompatible`] for any other precision. The result can be loaded into Redis with `SET` and then used with `PFCOUNT` and `PFMERGE`, but Redis will only agree on which elements it contains if they were pushed with [`push_redis`](Self::push_redis).
+	pub fn to_redis_bytes(&self) -> Result<Vec<u8>, Error> {
+		if self.p != redis::P {
+			return Err(Error::Incompatible(
+				"Redis HyperLogLogs have a precision of 14",
+			));
+		}
+		let m = match &self.m {
+			Registers::Sparse(sparse) => Cow::Owned(sparse.to_dense(self.p).into()),
+			m => m.unpacked(),
+		};
+		Ok(redis::encode(&m))
+	}
+
+	/// Deserialize a Redis HyperLogLog, as returned by `GET` on a key written with `PFADD` or `PFMERGE`, in either of its encodings.
+	///
+	/// The result has a precision of 14, dense registers, and no counters.
+	pub fn from_redis_bytes(bytes: &[u8]) -> Result<Self, Error>
+	where
+		S: Default,
+	{
+		let m = redis::decode(bytes)?;
+		let mut ret = Self::with_precision_and_hasher(redis::P, S::default());
+		let (zero, sum) = recount(&m);
+		ret.zero = zero;
+		ret.sum = sum;
+		ret.m = Registers::from_unpacked(m, ret.layout);
+		Ok(ret)
+	}
+
+	/// "Visit" an element hashed as [Apache DataSketches](https://datasketches.apache.org/docs/HLL/HLL.html)' `HllSketch::update(byte[])` hashes it, with MurmurHash3 and a seed of 9001, bypassing the hasher.
+	///
+	/// DataSketches hashes a `long` as its 8 little-endian bytes, and a `String` as its UTF-8 bytes. Elements pushed this way land in the same registers as in DataSketches, so the result of [`to_datasketches_bytes`](Self::to_datasketches_bytes) can be merged with sketches built there.
+	pub fn push_datasketches(&mut self, element: &[u8]) {
+		self.push_hash(datasketches::hash(element));
+	}
+
+	/// Serialize to the compact image of a DataSketches `HllSketch` with `lgK` equal to the precision, whose registers are stored as `hll_type`.
+	///
+	/// While few registers have been set, this is in the list or set mode, which hold coupons (the 26 bit slot and value of each element) rather than registers. DataSketches supports precisions of at most 21, so this returns [`Error::Incompatible`] for higher precisions.
+	pub fn to_datasketches_bytes(&self, hll_type: HllType) -> Result<Vec<u8>, Error> {
+		if self.p > datasketches::MAX_LG_K {
+			return Err(Error::Incompatible(
+				"DataSketches HLL sketches have a precision of at most 21",
+			));
+		}
+		Ok(match &self.m {
+			Registers::Sparse(sparse) if sparse.len() <= datasketches::max_coupons(self.p) => {
+				let coupons = sparse
+					.iter()
+					.map(|(index, rho)| datasketches::coupon(index, rho))
+					.collect::<Vec<_>>();
+				datasketches::encode_coupons(self.p, hll_type, &coupons)
+			}
+			Registers::Sparse(sparse) => {
+				datasketches::encode_registers(hll_type, &sparse.to_dense(self.p), self.len())
+			}
+			m => datasketches::encode_registers(hll_type, &m.unpacked(), self.len()),
+		})
+	}
+
+	/// Deserialize a DataSketches `HllSketch` image of any type and mode, compact or updatable.
+	///
+	/// The result has a precision of the sketch's `lgK`, and no counters.
+	pub fn from_datasketches_bytes(bytes: &[u8]) -> Result<Self, Error>
+	where
+		S: Default,
+	{
+		let (lg_k, image) = datasketches::decode(bytes)?;
+		let mut ret = Self::with_precision_and_hasher(lg_k, S::default());
+		match image {
+			datasketches::Image::Coupons(coupons) => {
+				for coupon in coupons {
+					ret.push_hash(datasketches::coupon_hash(coupon));
+				}
+			}
+			datasketches::Image::Registers(mut m) => {
+				// DataSketches caps values at 63 rather than `65 - p`
+				for x in &mut *m {
+					*x = (*x).min(65 - lg_k);
+				}
+				let (zero, sum) = recount(&m);
+				ret.zero = zero;
+				ret.sum = sum;
+				ret.m = Registers::from_unpacked(m, ret.layout);
+			}
+		}
+		Ok(ret)
 	}
 
-	/// Intersect another HyperLogLog data structure into `self`.
+	/// "Visit" an element hashed as [`postgresql-hll`](https://github.com/citusdata/postgresql-hll)'s `hll_hash_bytea` hashes it, with MurmurHash3 and a seed of 0, bypassing the hasher.
 	///
-	/// Note: This is different to an HLL approximating cardinality of the intersection of two multisets.
-	pub fn intersect(&mut self, src: &Self) {
-		assert_eq!(src.alpha, self.alpha);
-		assert_eq!(src.p, self.p);
-		assert_eq!(src.m.len(), self.m.len());
-		assert_eq!(src.counters.is_some(), self.counters.is_some());
-		#[cfg(all(
-			feature = "packed_simd",
-			any(target_arch = "x86", target_arch = "x86_64")
-		))]
-		{
-			assert_eq!(self.m.len() % u8s::lanes(), 0);
-			assert_eq!(u8s::lanes(), f32s::lanes() * 4);
-			assert_eq!(f32s::lanes(), u32s::lanes());
-			assert_eq!(u8sq::lanes(), u32s::lanes());
-			let mut zero = u8s_sad_out::splat(0);
-			let mut sum = f32s::splat(0.0);
-			for i in (0..self.m.len()).step_by(u8s::lanes()) {
-				unsafe {
-					let self_m = u8s::from_slice_unaligned_unchecked(self.m.get_unchecked(i..));
-					let src_m = u8s::from_slice_unaligned_unchecked(src.m.get_unchecked(i..));
-					let res = self_m.min(src_m);
-					res.write_to_slice_unaligned_unchecked(self.m.get_unchecked_mut(i..));
-					let count: u8s = u8s::splat(0) - u8s::from_bits(res.eq(u8s::splat(0)));
-					let count2 = Sad::<u8s>::sad(count, u8s::splat(0));
-					zero += count2;
-					for j in 0..4 {
-						let x = u8sq::from_slice_unaligned_unchecked(
-							self.m.get_unchecked(i + j * u8sq::lanes()..),
-						);
-						let x: u32s = x.cast();
-						let x: f32s = ((u32s::splat(u32::max_value()) - x) << 25 >> 2).into_bits();
-						sum += x;
-					}
+	/// `postgresql-hll` hashes an `integer` or `bigint` as its little-endian bytes, and `text` as its bytes. Elements pushed this way land in the same registers as in `postgresql-hll`, given a column with a `log2m` equal to the precision.
+	pub fn push_postgres(&mut self, element: &[u8]) {
+		if let Some(x) = postgres::arrange(postgres::hash(element), self.p) {
+			self.push_hash(x);
+		}
+	}
+
+	/// Serialize to the storage format of `postgresql-hll`, for a column with a `log2m` equal to the precision and the other type modifiers in `params`.
+	///
+	/// This uses the `EMPTY`, `SPARSE` or `FULL` representation, whichever is smallest. It never uses `EXPLICIT`, as that holds the hashes of the elements, which aren't retained. Returns [`Error::InvalidParameter`] if `params` aren't valid type modifiers.
+	pub fn to_postgres_bytes(&self, params: PostgresParams) -> Result<Vec<u8>, Error> {
+		let m = match &self.m {
+			Registers::Sparse(sparse) => Cow::Owned(sparse.to_dense(self.p).into()),
+			m => m.unpacked(),
+		};
+		postgres::encode(&m, params)
+	}
+
+	/// Deserialize a `postgresql-hll` value in any representation.
+	///
+	/// The result has a precision of the value's `log2m`, and no counters. Returns [`Error::Incompatible`] if `log2m` is outside of the supported precisions.
+	pub fn from_postgres_bytes(bytes: &[u8]) -> Result<Self, Error>
+	where
+		S: Default,
+	{
+		let (log2m, image) = postgres::decode(bytes)?;
+		let mut ret = Self::with_precision_and_hasher(log2m, S::default());
+		match image {
+			postgres::Image::Hashes(hashes) => {
+				for x in hashes
+					.into_iter()
+					.filter_map(|x| postgres::arrange(x, log2m))
+				{
+					ret.push_hash(x);
 				}
 			}
-			self.zero = usize::try_from(zero.wrapping_sum()).unwrap();
-			self.sum = f64::from(sum.sum());
-		}
-		#[cfg(not(all(
-			feature = "packed_simd",
-			any(target_arch = "x86", target_arch = "x86_64")
-		)))]
-		{
-			let mut zero = 0;
-			let mut sum = 0.0;
-			for (to, from) in self.m.iter_mut().zip(src.m.iter()) {
-				*to = (*to).min(*from);
-				zero += if *to == 0 { 1 } else { 0 };
-				sum += f64::from_bits(u64::max_value().wrapping_sub(u64::from(*to)) << 54 >> 2);
+			postgres::Image::Registers(mut m) => {
+				for x in &mut *m {
+					*x = (*x).min(65 - log2m);
+				}
+				let (zero, sum) = recount(&m);
+				ret.zero = zero;
+				ret.sum = sum;
+				ret.m = Registers::from_unpacked(m, ret.layout);
 			}
-			self.zero = zero;
-			self.sum = sum;
 		}
+		Ok(ret)
+	}
 
-		if let Some(counters) = &mut self.counters {
-			let max_width = 64 - self.p;
-			for i in 0..1 << self.p {
-				let to = &mut counters[i];
-				let from = &src.counters.as_ref().unwrap()[i];
-				// From 0 to max, merge the min counter
-				for j in 0..max_width {
-					let idx = j as usize;
-					let from_counter = &from[idx];
-					let to_counter = &mut to[idx];
-					if *to_counter > 0 || *from_counter > 0 {
-						if *to_counter as u16 + *from_counter as u16 > 128 {
-							if Self::is_change_power(
-								(*to_counter as u16 + *from_counter as u16 - 128) as u8,
-							) {
-								*to_counter += 1;
-							}
-						}
-						break;
-					}
-				}
-			}
+	/// The precision needed to achieve `error_rate`.
+	fn get_p(error_rate: f64) -> Result<u8, Error> {
+		if !(0.0 < error_rate && error_rate < 1.0) {
+			return Err(Error::InvalidParameter(
+				"error_rate must be between 0 and 1",
+			));
+		}
+		let p = (f64::log2(1.04 / error_rate) * 2.0).ceil();
+		if p < f64::from(MIN_P) {
+			return Err(Error::InvalidParameter(
+				"error_rate is too large, it would need a precision of less than 4",
+			));
+		}
+		if p > f64::from(MAX_P) {
+			return Err(Error::InvalidParameter(
+				"error_rate is too small, it would need a precision of more than 24",
+			));
 		}
+		Ok(f64_to_u8(p))
 	}
 
-	/// Clears the `HyperLogLog` data structure, as if it was new.
-	pub fn clear(&mut self) {
-		let max_width = 64 - self.p;
-		self.zero = self.m.len();
-		self.sum = usize_to_f64(self.m.len());
-		self.m.iter_mut().for_each(|x| {
-			*x = 0;
-		});
-		if let Some(counters) = &mut self.counters {
-			counters.iter_mut().for_each(|x| {
-				*x = vec![0; max_width as usize].into_boxed_slice();
-			});
+	fn check_p(p: u8) -> Result<(), Error> {
+		if !(MIN_P..=MAX_P).contains(&p) {
+			return Err(Error::InvalidParameter(
+				"precision must be between 4 and 24",
+			));
 		}
+		Ok(())
 	}
 
 	fn get_threshold(p: u8) -> f64 {
@@ -496,7 +1286,7 @@ where
 	}
 
 	fn get_alpha(p: u8) -> f64 {
-		assert!(4 <= p && p <= 16);
+		debug_assert!((MIN_P..=MAX_P).contains(&p));
 		match p {
 			4 => 0.673,
 			5 => 0.697,
@@ -505,12 +1295,6 @@ where
 		}
 	}
 
-	fn get_rho(w: u64, max_width: u8) -> u8 {
-		let rho = max_width - (64 - u8::try_from(w.leading_zeros()).unwrap()) + 1;
-		assert!(0 < rho && rho < 65);
-		rho
-	}
-
 	fn estimate_bias(e: f64, p: u8) -> f64 {
 		let bias_vector = BIAS_DATA[(p - 4) as usize];
 		let neighbors = Self::get_nearest_neighbors(e, RAW_ESTIMATE_DATA[(p - 4) as usize]);
@@ -545,8 +1329,9 @@ where
 	}
 
 	fn ep(&self) -> f64 {
-		let e = self.alpha * usize_to_f64(self.m.len() * self.m.len()) / self.sum;
-		if e <= usize_to_f64(5 * self.m.len()) {
+		let m = 1_usize << self.p;
+		let e = self.alpha * usize_to_f64(m * m) / self.sum;
+		if e <= usize_to_f64(5 * m) {
 			e - Self::estimate_bias(e, self.p)
 		} else {
 			e
@@ -554,7 +1339,7 @@ where
 	}
 }
 
-impl<V: ?Sized> Clone for HyperLogLog<V> {
+impl<V: ?Sized, S: Clone> Clone for HyperLogLog<V, S> {
 	fn clone(&self) -> Self {
 		Self {
 			alpha: self.alpha,
@@ -563,13 +1348,18 @@ impl<V: ?Sized> Clone for HyperLogLog<V> {
 			p: self.p,
 			m: self.m.clone(),
 			counters: self.counters.clone(),
+			rng: self.rng,
+			estimator: self.estimator,
+			layout: self.layout,
+			hasher: self.hasher.clone(),
 			marker: PhantomData,
 		}
 	}
 }
-impl<V: ?Sized> fmt::Debug for HyperLogLog<V>
+impl<V: ?Sized, S> fmt::Debug for HyperLogLog<V, S>
 where
 	V: Hash,
+	S: BuildHasher,
 {
 	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
 		fmt.debug_struct("HyperLogLog")
@@ -578,39 +1368,57 @@ where
 	}
 }
 
-impl<V: ?Sized> PartialEq for HyperLogLog<V>
+/// `HyperLogLog`s are equal if they have the same precision, registers and deletion counters, if any.
+///
+/// Registers are compared regardless of their [`RegisterLayout`], but a sparse `HyperLogLog` only equals a dense one if both are empty, as the sparse representation holds the registers at a higher precision.
+impl<V: ?Sized, S> PartialEq for HyperLogLog<V, S>
 where
 	V: Hash,
+	S: BuildHasher,
 {
 	fn eq(&self, other: &Self) -> bool {
-		if self.len().eq(&other.len()) {
-			if let Some(self_counters) = &self.counters {
-				if let Some(other_counters) = &other.counters {
-					return other_counters == self_counters;
-				} else {
-					return false;
+		self.p == other.p
+			&& match (&self.m, &other.m) {
+				(Registers::Sparse(a), Registers::Sparse(b)) => a == b,
+				(Registers::Sparse(_), _) | (_, Registers::Sparse(_)) => {
+					self.is_empty() && other.is_empty()
 				}
-			}
-		}
-
-		false
+				(a, b) => a.unpacked() == b.unpacked(),
+			} && self.counters == other.counters
 	}
 }
 
-impl<V: ?Sized> Eq for HyperLogLog<V> where V: Hash {}
+impl<V: ?Sized, S> Eq for HyperLogLog<V, S>
+where
+	V: Hash,
+	S: BuildHasher,
+{
+}
+
+impl<V: ?Sized, S> Hash for HyperLogLog<V, S>
+where
+	V: Hash,
+	S: BuildHasher,
+{
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.hash_contents(state);
+	}
+}
 
-impl<V: ?Sized> New for HyperLogLog<V>
+impl<V: ?Sized, S> New for HyperLogLog<V, S>
 where
 	V: Hash,
+	S: BuildHasher + Default,
 {
 	type Config = f64;
 	fn new(config: &Self::Config) -> Self {
-		Self::new(*config)
+		Self::with_hasher(*config, S::default())
 	}
 }
-impl<V: ?Sized> Intersect for HyperLogLog<V>
+impl<V: ?Sized, S> Intersect for HyperLogLog<V, S>
 where
 	V: Hash,
+	S: BuildHasher + Clone + PartialEq,
 {
 	fn intersect<'a>(mut iter: impl Iterator<Item = &'a Self>) -> Option<Self>
 	where
@@ -623,150 +1431,181 @@ where
 		Some(ret)
 	}
 }
-impl<'a, V: ?Sized> UnionAssign<&'a HyperLogLog<V>> for HyperLogLog<V>
+impl<'a, V: ?Sized, S> UnionAssign<&'a HyperLogLog<V, S>> for HyperLogLog<V, S>
 where
 	V: Hash,
+	S: BuildHasher + PartialEq,
 {
 	fn union_assign(&mut self, rhs: &'a Self) {
 		self.union(rhs)
 	}
 }
-impl<'a, V: ?Sized> ops::AddAssign<&'a V> for HyperLogLog<V>
+impl<'a, V: ?Sized, S> ops::AddAssign<&'a V> for HyperLogLog<V, S>
 where
 	V: Hash,
+	S: BuildHasher,
 {
 	fn add_assign(&mut self, rhs: &'a V) {
 		self.push(rhs)
 	}
 }
-impl<'a, V: ?Sized> ops::AddAssign<&'a Self> for HyperLogLog<V>
+impl<'a, V: ?Sized, S> ops::AddAssign<&'a Self> for HyperLogLog<V, S>
 where
 	V: Hash,
+	S: BuildHasher + PartialEq,
 {
 	fn add_assign(&mut self, rhs: &'a Self) {
 		self.union(rhs)
 	}
 }
-impl<V: ?Sized> IntersectPlusUnionIsPlus for HyperLogLog<V> {
+impl<V: ?Sized, S> IntersectPlusUnionIsPlus for HyperLogLog<V, S> {
 	const VAL: bool = true;
 }
 
-#[cfg(all(
-	feature = "packed_simd",
-	any(target_arch = "x86", target_arch = "x86_64")
-))]
-mod simd {
-	pub use packed_simd::{self, Cast, FromBits, IntoBits};
-	use std::marker::PhantomData;
-
-	#[cfg(target_feature = "avx512bw")] // TODO
-	mod simd_types {
-		use super::packed_simd;
-		pub type u8s = packed_simd::u8x64;
-		pub type u8s_sad_out = packed_simd::u64x8;
-		pub type f32s = packed_simd::f32x16;
-		pub type u32s = packed_simd::u32x16;
-		pub type u8sq = packed_simd::u8x16;
-	}
-	#[cfg(target_feature = "avx2")]
-	mod simd_types {
-		#![allow(non_camel_case_types)]
-		use super::packed_simd;
-		pub type u8s = packed_simd::u8x32;
-		pub type u8s_sad_out = packed_simd::u64x4;
-		pub type f32s = packed_simd::f32x8;
-		pub type u32s = packed_simd::u32x8;
-		pub type u8sq = packed_simd::u8x8;
-	}
-	#[cfg(all(not(target_feature = "avx2"), target_feature = "sse2"))]
-	mod simd_types {
-		#![allow(non_camel_case_types)]
-		use super::packed_simd;
-		pub type u8s = packed_simd::u8x16;
-		pub type u8s_sad_out = packed_simd::u64x2;
-		pub type f32s = packed_simd::f32x4;
-		pub type u32s = packed_simd::u32x4;
-		pub type u8sq = packed_simd::u8x4;
-	}
-	#[cfg(all(not(target_feature = "avx2"), not(target_feature = "sse2")))]
-	mod simd_types {
-		#![allow(non_camel_case_types)]
-		use super::packed_simd;
-		pub type u8s = packed_simd::u8x8;
-		pub type u8s_sad_out = u64;
-		pub type f32s = packed_simd::f32x2;
-		pub type u32s = packed_simd::u32x2;
-		pub type u8sq = packed_simd::u8x2;
-	}
-	pub use self::simd_types::{f32s, u32s, u8s, u8s_sad_out, u8sq};
-
-	pub struct Sad<X>(PhantomData<fn(X)>);
-	#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-	mod x86 {
-		#[cfg(target_arch = "x86")]
-		pub use std::arch::x86::*;
-		#[cfg(target_arch = "x86_64")]
-		pub use std::arch::x86_64::*;
-	}
-	// TODO
-	// #[cfg(target_feature = "avx512bw")]
-	// impl Sad<packed_simd::u8x64> {
-	// 	#[inline]
-	// 	#[target_feature(enable = "avx512bw")]
-	// 	pub unsafe fn sad(a: packed_simd::u8x64, b: packed_simd::u8x64) -> packed_simd::u64x8 {
-	// 		use std::mem::transmute;
-	// 		packed_simd::Simd(transmute(x86::_mm512_sad_epu8(transmute(a.0), transmute(b.0))))
-	// 	}
-	// }
-	#[cfg(target_feature = "avx2")]
-	impl Sad<packed_simd::u8x32> {
-		#[inline]
-		#[target_feature(enable = "avx2")]
-		pub unsafe fn sad(a: packed_simd::u8x32, b: packed_simd::u8x32) -> packed_simd::u64x4 {
-			use std::mem::transmute;
-			packed_simd::Simd(transmute(x86::_mm256_sad_epu8(
-				transmute(a.0),
-				transmute(b.0),
-			)))
-		}
-	}
-	#[cfg(target_feature = "sse2")]
-	impl Sad<packed_simd::u8x16> {
-		#[inline]
-		#[target_feature(enable = "sse2")]
-		pub unsafe fn sad(a: packed_simd::u8x16, b: packed_simd::u8x16) -> packed_simd::u64x2 {
-			use std::mem::transmute;
-			packed_simd::Simd(transmute(x86::_mm_sad_epu8(transmute(a.0), transmute(b.0))))
-		}
-	}
-	#[cfg(target_feature = "sse,mmx")]
-	impl Sad<packed_simd::u8x8> {
-		#[inline]
-		#[target_feature(enable = "sse,mmx")]
-		pub unsafe fn sad(a: packed_simd::u8x8, b: packed_simd::u8x8) -> u64 {
-			use std::mem::transmute;
-			transmute(x86::_mm_sad_pu8(transmute(a.0), transmute(b.0)))
-		}
-	}
-	#[cfg(not(target_feature = "sse,mmx"))]
-	impl Sad<packed_simd::u8x8> {
-		#[inline(always)]
-		pub unsafe fn sad(a: packed_simd::u8x8, b: packed_simd::u8x8) -> u64 {
-			assert_eq!(b, packed_simd::u8x8::splat(0));
-			(0..8).map(|i| u64::from(a.extract(i))).sum()
-		}
-	}
-}
-#[cfg(all(
-	feature = "packed_simd",
-	any(target_arch = "x86", target_arch = "x86_64")
-))]
-use simd::{f32s, u32s, u8s, u8s_sad_out, u8sq, Cast, FromBits, IntoBits, Sad};
+/// The registers of a [`HyperLogLog`], in either the sparse or one of the dense representations.
+#[derive(Clone, Serialize, Deserialize)]
+enum Registers {
+	Sparse(Sparse),
+	Dense(Box<[u8]>),
+	Packed(Packed6),
+}
+impl Registers {
+	/// Empty registers, in the sparse representation if it's more precise than `p`.
+	fn new(p: u8, layout: RegisterLayout) -> Self {
+		if p < P_SPARSE {
+			Registers::Sparse(Sparse::new())
+		} else {
+			Self::dense(p, layout)
+		}
+	}
+
+	/// Empty dense registers of precision `p`.
+	fn dense(p: u8, layout: RegisterLayout) -> Self {
+		match layout {
+			RegisterLayout::Byte => Registers::Dense(vec![0; 1 << p].into_boxed_slice()),
+			RegisterLayout::Packed6 => Registers::Packed(Packed6::new(1 << p)),
+		}
+	}
+
+	/// Dense registers from one byte per register.
+	fn from_unpacked(m: Box<[u8]>, layout: RegisterLayout) -> Self {
+		match layout {
+			RegisterLayout::Byte => Registers::Dense(m),
+			RegisterLayout::Packed6 => Registers::Packed(Packed6::from_registers(&m)),
+		}
+	}
+
+	/// The layout of the dense registers, or `None` if they're sparse.
+	fn layout(&self) -> Option<RegisterLayout> {
+		match self {
+			Registers::Sparse(_) => None,
+			Registers::Dense(_) => Some(RegisterLayout::Byte),
+			Registers::Packed(_) => Some(RegisterLayout::Packed6),
+		}
+	}
+
+	/// The dense registers as one byte per register, unpacking them if necessary.
+	fn unpacked(&self) -> Cow<'_, [u8]> {
+		match self {
+			Registers::Dense(m) => Cow::Borrowed(m),
+			Registers::Packed(m) => Cow::Owned(m.to_registers().into()),
+			Registers::Sparse(_) => unreachable!("registers are in the sparse representation"),
+		}
+	}
+
+	#[inline]
+	fn get(&self, index: usize) -> u8 {
+		match self {
+			Registers::Dense(m) => m[index],
+			Registers::Packed(m) => m.get(index),
+			Registers::Sparse(_) => unreachable!("registers are in the sparse representation"),
+		}
+	}
+
+	#[inline]
+	fn set(&mut self, index: usize, x: u8) {
+		match self {
+			Registers::Dense(m) => m[index] = x,
+			Registers::Packed(m) => m.set(index, x),
+			Registers::Sparse(_) => unreachable!("registers are in the sparse representation"),
+		}
+	}
+
+	fn dense_mut(&mut self) -> &mut [u8] {
+		match self {
+			Registers::Dense(m) => m,
+			Registers::Packed(_) => unreachable!("registers are packed"),
+			Registers::Sparse(_) => unreachable!("registers are in the sparse representation"),
+		}
+	}
+}
+
+/// Call `f` with the register index and `rho` of each of the hashes `xs`, at precision `p`.
+fn for_each_register(xs: &[u64], p: u8, mut f: impl FnMut(usize, u8)) {
+	for &x in xs {
+		f(
+			usize::try_from(x & ((1 << p) - 1)).unwrap(),
+			get_rho(x >> p, 64 - p),
+		);
+	}
+}
+
+/// Merge `src` into the packed registers `m` with `f`, returning the new count of zero registers and sum of 2<sup>-register</sup>.
+fn merge_packed(m: &mut Packed6, src: &[u8], f: fn(u8, u8) -> u8) -> (usize, f64) {
+	assert_eq!(m.len(), src.len());
+	let mut zero = 0;
+	let mut sum = 0.0;
+	for (i, &from) in src.iter().enumerate() {
+		let to = f(m.get(i), from);
+		m.set(i, to);
+		zero += usize::from(to == 0);
+		// see pow_bithack()
+		sum += f64::from_bits(u64::MAX.wrapping_sub(u64::from(to)) << 54 >> 2);
+	}
+	(zero, sum)
+}
+
+/// Fold the dense registers `m` of precision `from` down to precision `to`.
+fn fold_dense(m: &[u8], from: u8, to: u8) -> Box<[u8]> {
+	let mut ret = vec![0; 1 << to].into_boxed_slice();
+	let mask = (1 << to) - 1;
+	for (index, &rho) in m.iter().enumerate() {
+		if rho == 0 {
+			continue;
+		}
+		let rho = fold_rho(u64::try_from(index).unwrap(), rho, from, to);
+		let register = &mut ret[index & mask];
+		*register = (*register).max(rho);
+	}
+	ret
+}
+
+fn get_rho(w: u64, max_width: u8) -> u8 {
+	let rho = max_width - (64 - u8::try_from(w.leading_zeros()).unwrap()) + 1;
+	assert!(0 < rho && rho < 65);
+	rho
+}
+
+/// Given the `rho` of register `index` at precision `from`, calculate the `rho` it would have had at the lower precision `to`.
+///
+/// The `from - to` high bits of `index` become the low bits of `w`, so they only matter if the rest of `w` was zero.
+fn fold_rho(index: u64, rho: u8, from: u8, to: u8) -> u8 {
+	debug_assert!(to <= from);
+	if rho <= 64 - from {
+		rho
+	} else {
+		64 - from + get_rho(index >> to, from - to)
+	}
+}
 
 #[cfg(test)]
 mod test {
-	use super::{super::f64_to_usize, HyperLogLog};
-	use std::f64;
+	use super::{
+		super::f64_to_usize, CounterLayout, Error, Estimator, HllType, HyperLogLog, HyperLogLogMagnitude, Intersect, New, Packed6, PostgresParams, RegisterLayout, Registers, UnionAssign, XxHashBuilder
+	};
+	use std::{
+		collections::{hash_map::DefaultHasher, HashSet}, convert::TryFrom, f64, hash::{BuildHasherDefault, Hasher}
+	};
 
 	#[test]
 	fn pow_bithack() {
@@ -887,4 +1726,770 @@ mod test {
 		// after second delete, len change to 0.
 		assert_eq!(0 as f64, hll.len());
 	}
+
+	#[test]
+	fn sparse() {
+		let mut sparse = HyperLogLog::new(0.01);
+		let mut dense = HyperLogLog::new_with_counters(0.01);
+		for i in 0..200 {
+			sparse.push(&i);
+			dense.push(&i);
+		}
+		assert!(matches!(sparse.m, Registers::Sparse(_)));
+		assert!((sparse.len().round() - 200.0).abs() < f64::EPSILON);
+		sparse.densify();
+		assert_eq!(sparse.m.dense_mut(), dense.m.dense_mut());
+		assert_eq!(sparse.len(), dense.len());
+	}
+
+	#[test]
+	fn sparse_union() {
+		let actual = 100_000;
+		let p = 0.01;
+		let mut hll1 = HyperLogLog::new(p);
+		let mut hll2 = HyperLogLog::new(p);
+		for i in 0..100 {
+			hll1.push(&i);
+		}
+		for i in 100..actual {
+			hll2.push(&i);
+		}
+		assert!(matches!(hll1.m, Registers::Sparse(_)));
+		assert!(matches!(hll2.m, Registers::Dense(_)));
+		let mut hll3 = hll1.clone();
+		hll3.union(&hll2);
+		hll2.union(&hll1);
+		assert_eq!(hll2.m.dense_mut(), hll3.m.dense_mut());
+		let actual = f64::from(actual);
+		assert!(hll2.len() > (actual - (actual * p * 3.0)));
+		assert!(hll2.len() < (actual + (actual * p * 3.0)));
+
+		let mut hll4 = HyperLogLog::new(p);
+		for i in 50..150 {
+			hll4.push(&i);
+		}
+		hll1.union(&hll4);
+		assert!(matches!(hll1.m, Registers::Sparse(_)));
+		assert!((hll1.len().round() - 150.0).abs() < f64::EPSILON);
+	}
+
+	#[test]
+	fn hasher() {
+		let mut hll1 = HyperLogLog::with_hasher(0.01, XxHashBuilder::with_seed(1));
+		let mut hll2 = HyperLogLog::with_hasher(0.01, XxHashBuilder::with_seed(1));
+		let mut hll3 = HyperLogLog::new(0.01);
+		for i in 0..10_000 {
+			hll1.push(&i);
+			hll2.push(&i);
+			hll3.push(&i);
+		}
+		assert_eq!(hll1.m.dense_mut(), hll2.m.dense_mut());
+		assert_ne!(hll1.m.dense_mut(), hll3.m.dense_mut());
+		assert!(hll1.try_union(&hll2).is_ok());
+		assert!(matches!(hll1.try_union(&hll3), Err(Error::Incompatible(_))));
+		assert!(matches!(
+			hll1.try_intersect(&hll3),
+			Err(Error::Incompatible(_))
+		));
+		assert!(matches!(
+			hll1.try_jaccard(&hll3),
+			Err(Error::Incompatible(_))
+		));
+
+		let mut hll =
+			HyperLogLog::with_hasher(0.01, BuildHasherDefault::<DefaultHasher>::default());
+		for i in 0..100 {
+			hll.push(&i);
+		}
+		assert!((hll.len().round() - 100.0).abs() < f64::EPSILON);
+	}
+
+	#[test]
+	fn fallible() {
+		for &error_rate in &[0.0, 1.0, -0.5, f64::NAN, 0.5, 0.0001] {
+			assert!(matches!(
+				HyperLogLog::<u64>::try_new(error_rate),
+				Err(Error::InvalidParameter(_))
+			));
+		}
+		for &p in &[0, 3, 25, 64] {
+			assert!(matches!(
+				HyperLogLog::<u64>::try_with_precision(p),
+				Err(Error::InvalidParameter(_))
+			));
+		}
+		let mut hll = HyperLogLog::<u64>::try_new(0.01).unwrap();
+		let other = HyperLogLog::try_new(0.05).unwrap();
+		assert!(matches!(
+			hll.clone().try_reduce_precision(15),
+			Err(Error::InvalidParameter(_))
+		));
+		assert!(matches!(
+			hll.clone().try_reduce_precision(3),
+			Err(Error::InvalidParameter(_))
+		));
+		assert!(matches!(
+			HyperLogLog::<u64>::new_with_counters(0.05).try_union(&other),
+			Err(Error::Incompatible(_))
+		));
+		assert!(matches!(
+			hll.try_intersect(&other),
+			Err(Error::Incompatible(_))
+		));
+		let mut counters = HyperLogLog::<u64>::try_new_with_counters(0.01).unwrap();
+		assert!(matches!(
+			counters.try_union(&hll),
+			Err(Error::Incompatible(_))
+		));
+		assert!(hll.try_union(&HyperLogLog::new(0.01)).is_ok());
+		for &confidence in &[0.0, 1.0, f64::NAN] {
+			assert!(matches!(
+				hll.try_len_with_bounds(confidence),
+				Err(Error::InvalidParameter(_))
+			));
+		}
+	}
+
+	#[test]
+	fn high_precision() {
+		for &p in &[17, 18, 20, 24] {
+			let mut hll = HyperLogLog::with_precision(p);
+			assert_eq!(hll.precision(), p);
+			let mut actual = 0;
+			for &n in &[1_000, 300_000, 2_000_000] {
+				while actual < n {
+					hll.push(&actual);
+					actual += 1;
+				}
+				let error = 1.04 / f64::from(1 << p).sqrt();
+				let estimate = hll.len();
+				assert!(
+					(estimate - f64::from(actual)).abs() / f64::from(actual) < error * 5.0,
+					"p: {}, actual: {}, estimate: {}",
+					p,
+					actual,
+					estimate
+				);
+			}
+		}
+		assert_eq!(HyperLogLog::<u64>::new(0.0005).precision(), 23);
+	}
+
+	#[test]
+	fn estimators() {
+		for &p in &[4, 10, 14, 20] {
+			let mut hlls = [
+				Estimator::HyperLogLogPlusPlus,
+				Estimator::Improved,
+				Estimator::MaximumLikelihood,
+			]
+			.iter()
+			.map(|&estimator| {
+				let mut hll = HyperLogLog::with_precision(p);
+				hll.set_estimator(estimator);
+				hll
+			})
+			.collect::<Vec<_>>();
+			for hll in &hlls {
+				assert!(hll.len().abs() < f64::EPSILON);
+			}
+			let mut actual = 0_u32;
+			for &n in &[10, 1_000, 100_000, 1_000_000] {
+				while actual < n {
+					for hll in &mut hlls {
+						hll.push(&actual);
+					}
+					actual += 1;
+				}
+				let error = 1.04 / f64::from(1 << p).sqrt();
+				for hll in &mut hlls {
+					for _ in 0..2 {
+						let estimate = hll.len();
+						assert!(
+							(estimate - f64::from(actual)).abs() / f64::from(actual) < error * 5.0,
+							"{:?}, p: {}, actual: {}, estimate: {}",
+							hll.estimator(),
+							p,
+							actual,
+							estimate
+						);
+						hll.densify();
+					}
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn set_operations() {
+		let mut a = HyperLogLog::new(0.01);
+		let mut b = HyperLogLog::new(0.01);
+		for i in 0..100_000 {
+			a.push(&i);
+		}
+		for i in 50_000..200_000 {
+			b.push(&i);
+		}
+		let close = |estimate: f64, actual: f64| (estimate - actual).abs() / actual < 0.05;
+		assert!(close(a.intersection_len(&b), 50_000.0));
+		assert!(close(HyperLogLog::intersection_len(&b, &a), 50_000.0));
+		assert!(close(a.difference_len(&b), 50_000.0));
+		assert!(close(b.difference_len(&a), 100_000.0));
+		assert!(close(a.jaccard(&b), 0.25));
+		assert!(close(a.jaccard(&a), 1.0));
+
+		let empty = HyperLogLog::new(0.01);
+		assert!(a.intersection_len(&empty).abs() < f64::EPSILON);
+		assert!(empty.jaccard(&HyperLogLog::new(0.01)).abs() < f64::EPSILON);
+		assert!(matches!(
+			a.try_jaccard(&HyperLogLog::new_with_counters(0.05)),
+			Err(Error::Incompatible(_))
+		));
+	}
+
+	#[test]
+	fn reduce_precision() {
+		for &n in &[100, 100_000] {
+			let mut high = HyperLogLog::with_precision(16);
+			let mut low = HyperLogLog::with_precision(10);
+			let mut other = HyperLogLog::with_precision(12);
+			for i in 0..n {
+				high.push(&i);
+				low.push(&i);
+				other.push(&(i + n / 2));
+			}
+			let mut reduced = high.clone();
+			reduced.reduce_precision(10);
+			assert_eq!(reduced.precision(), 10);
+			reduced.densify();
+			low.densify();
+			assert_eq!(reduced.m.dense_mut(), low.m.dense_mut());
+			assert_eq!(reduced.len(), low.len());
+
+			// union folds down to the lower precision, whichever side it's on
+			let mut union1 = high.clone();
+			union1.union(&other);
+			let mut union2 = other.clone();
+			union2.union(&high);
+			assert_eq!(union1.precision(), 12);
+			assert_eq!(union2.precision(), 12);
+			union1.densify();
+			union2.densify();
+			assert_eq!(union1.m.dense_mut(), union2.m.dense_mut());
+			let actual = f64::from(n + n / 2);
+			assert!((union1.len() - actual).abs() / actual < 0.05);
+		}
+	}
+
+	#[test]
+	fn packed() {
+		let mut packed = Packed6::new(1 << 4);
+		for i in 0..16 {
+			packed.set(i, 63 - u8::try_from(i).unwrap());
+		}
+		packed.set(5, 0);
+		for i in 0..16 {
+			let expected = if i == 5 { 0 } else { 63 - i };
+			assert_eq!(usize::from(packed.get(i)), expected);
+		}
+		assert_eq!(packed.as_bytes().len(), 13);
+		assert!(packed.is_valid());
+
+		let mut byte = HyperLogLog::new(0.01);
+		let mut packed = HyperLogLog::new(0.01);
+		packed.set_register_layout(RegisterLayout::Packed6);
+		let mut other = HyperLogLog::new(0.01);
+		for i in 0..100_000 {
+			byte.push(&i);
+			packed.push(&i);
+			other.push(&(i * 2));
+		}
+		assert!(matches!(packed.m, Registers::Packed(_)));
+		assert_eq!(byte.len(), packed.len());
+		assert_eq!(byte.m.unpacked(), packed.m.unpacked());
+		let mut unpacked = packed.clone();
+		unpacked.set_register_layout(RegisterLayout::Byte);
+		assert_eq!(byte.m.dense_mut(), unpacked.m.dense_mut());
+
+		let mut intersection = byte.clone();
+		intersection.intersect(&other);
+		let mut packed_intersection = packed.clone();
+		packed_intersection.intersect(&other);
+		assert_eq!(intersection.len(), packed_intersection.len());
+		assert_eq!(intersection.m.unpacked(), packed_intersection.m.unpacked());
+		let mut union = byte.clone();
+		union.union(&other);
+		packed.union(&other);
+		assert_eq!(union.len(), packed.len());
+		packed.reduce_precision(10);
+		union.reduce_precision(10);
+		assert_eq!(union.m.unpacked(), packed.m.unpacked());
+		assert_eq!(packed.register_layout(), RegisterLayout::Packed6);
+		packed.clear();
+		assert!(packed.is_empty());
+
+		let mut hll = HyperLogLog::<u64>::with_precision(4);
+		hll.set_register_layout(RegisterLayout::Packed6);
+		for i in 0..100 {
+			hll.push(&i);
+		}
+		let json = serde_json::to_string(&hll).unwrap();
+		let copy: HyperLogLog<u64> = serde_json::from_str(&json).unwrap();
+		assert_eq!(copy, hll);
+		// a length that doesn't match the bytes, or that would be unpacked into a huge allocation
+		for len in &[15, 17, 1000, usize::MAX] {
+			let json = json.replace("\"len\":16", &format!("\"len\":{len}"));
+			assert!(serde_json::from_str::<HyperLogLog<u64>>(&json).is_err());
+		}
+	}
+
+	#[test]
+	fn counter_layouts() {
+		let mut dense = HyperLogLog::new_with_counters(0.01);
+		let mut sparse = HyperLogLog::new_with_counters(0.01);
+		sparse.set_counter_layout(CounterLayout::Sparse);
+		assert_eq!(sparse.counter_layout(), Some(CounterLayout::Sparse));
+		for i in 0..1_000 {
+			dense.push(&i);
+			sparse.push(&i);
+		}
+		assert!(dense.counters == sparse.counters);
+		let mut other = HyperLogLog::new_with_counters(0.01);
+		for i in 500..2_000 {
+			other.push(&i);
+		}
+		let (mut dense_union, mut sparse_union) = (dense.clone(), sparse.clone());
+		dense_union.union(&other);
+		sparse_union.union(&other);
+		assert!(dense_union.counters == sparse_union.counters);
+		assert_eq!(dense_union.len(), sparse_union.len());
+		dense.intersect(&other);
+		sparse.intersect(&other);
+		assert!(dense.counters == sparse.counters);
+		for i in 0..1_000 {
+			dense_union.delete(&i);
+			sparse_union.delete(&i);
+		}
+		assert!(dense_union.counters == sparse_union.counters);
+		assert_eq!(dense_union.len(), sparse_union.len());
+		sparse_union.set_counter_layout(CounterLayout::Dense);
+		assert!(dense_union.counters == sparse_union.counters);
+		sparse.clear();
+		assert!(sparse.is_empty());
+		assert_eq!(sparse.counter_layout(), Some(CounterLayout::Sparse));
+		assert!(sparse.counters == HyperLogLog::<u32>::new_with_counters(0.01).counters);
+	}
+
+	#[test]
+	fn counters_max_rho() {
+		// a hash of 0 has the largest possible rho
+		#[derive(Default)]
+		struct ZeroHasher;
+		impl Hasher for ZeroHasher {
+			fn finish(&self) -> u64 {
+				0
+			}
+			fn write(&mut self, _bytes: &[u8]) {}
+		}
+		let mut hll = HyperLogLog::with_counters_and_hasher(
+			0.01,
+			BuildHasherDefault::<ZeroHasher>::default(),
+		);
+		hll.push(&0);
+		assert_eq!(hll.m.dense_mut()[0], 65 - hll.p);
+		hll.delete(&0);
+		assert!(hll.is_empty());
+	}
+
+	#[test]
+	fn counter_seed() {
+		let replay = |seed| {
+			let mut hll = HyperLogLog::new_with_counters(0.05);
+			hll.set_counter_seed(seed);
+			for i in 0..1_000 {
+				for _ in 0..200 {
+					hll.push(&i);
+				}
+			}
+			for i in 0..1_000 {
+				for _ in 0..100 {
+					hll.delete(&i);
+				}
+			}
+			hll
+		};
+		assert!(replay(1).counters == replay(1).counters);
+		assert!(replay(1).counters != replay(2).counters);
+	}
+
+	#[test]
+	fn redis() {
+		// `PFADD key` on a new key
+		let empty = b"HYLL\x01\0\0\0\0\0\0\0\0\0\0\0\x7f\xff";
+		let hll = HyperLogLog::<[u8]>::from_redis_bytes(empty).unwrap();
+		assert_eq!(hll.len(), 0.0);
+		let bytes = hll.to_redis_bytes().unwrap();
+		assert_eq!(bytes[..15], empty[..15]);
+		assert_eq!(bytes[16..], empty[16..]);
+
+		// register 0 is 3, register 16383 is 32
+		let mut sparse = empty[..16].to_vec();
+		sparse.extend_from_slice(&[0x88, 0x7f, 0xfd, 0xfc]);
+		let hll = HyperLogLog::<[u8]>::from_redis_bytes(&sparse).unwrap();
+		let m = hll.m.unpacked();
+		assert_eq!((m[0], m[16383]), (3, 32));
+		assert_eq!(m.iter().filter(|&&x| x != 0).count(), 2);
+		assert_eq!(hll.to_redis_bytes().unwrap()[16..], sparse[16..]);
+
+		let mut small = HyperLogLog::<[u8]>::with_precision(14);
+		let mut large = HyperLogLog::<[u8]>::with_precision(14);
+		for i in 0..100_000_u32 {
+			let element = i.to_string();
+			if i < 1000 {
+				small.push_redis(element.as_bytes());
+			}
+			large.push_redis(element.as_bytes());
+		}
+		let small_bytes = small.to_redis_bytes().unwrap();
+		let large_bytes = large.to_redis_bytes().unwrap();
+		assert_eq!(small_bytes[4], 1);
+		assert_eq!((large_bytes[4], large_bytes.len()), (0, 16 + 12288));
+		for bytes in &[small_bytes, large_bytes] {
+			let hll = HyperLogLog::<[u8]>::from_redis_bytes(bytes).unwrap();
+			assert_eq!(&hll.to_redis_bytes().unwrap(), bytes);
+		}
+		assert!((small.len() - 1000.0).abs() < 50.0);
+		assert!((large.len() - 100_000.0).abs() < 5000.0);
+		let mut union =
+			HyperLogLog::<[u8]>::from_redis_bytes(&small.to_redis_bytes().unwrap()).unwrap();
+		union.union(&large);
+		assert_eq!(
+			union.to_redis_bytes().unwrap(),
+			large.to_redis_bytes().unwrap()
+		);
+
+		let mut truncated = large.to_redis_bytes().unwrap();
+		truncated.truncate(truncated.len() - 1);
+		let mut overflow = sparse.clone();
+		overflow.push(0x80);
+		for bytes in &[
+			&b"HYLX\x01\0\0\0\0\0\0\0\0\0\0\0\x7f\xff"[..],
+			&empty[..17],
+			&truncated,
+			&overflow,
+		] {
+			assert!(matches!(
+				HyperLogLog::<[u8]>::from_redis_bytes(bytes),
+				Err(Error::Deserialization(_))
+			));
+		}
+		assert!(matches!(
+			HyperLogLog::<[u8]>::with_precision(12).to_redis_bytes(),
+			Err(Error::Incompatible(_))
+		));
+	}
+
+	#[test]
+	fn datasketches() {
+		// `new HllSketch(12, TgtHllType.HLL_8).toCompactByteArray()`
+		let empty = [2, 1, 7, 12, 3, 0x0c, 0, 0x08];
+		let hll = HyperLogLog::<[u8]>::from_datasketches_bytes(&empty).unwrap();
+		assert_eq!(hll.len(), 0.0);
+		assert_eq!(hll.to_datasketches_bytes(HllType::Hll8).unwrap(), empty);
+
+		// an updatable list with two coupons
+		let mut list = vec![2, 1, 7, 12, 3, 0, 2, 0x08];
+		for &coupon in &[0, 3 << 26 | 0x11, 0, 0, 1 << 26 | 0xfa0, 0, 0, 0_u32] {
+			list.extend_from_slice(&coupon.to_le_bytes());
+		}
+		let hll = HyperLogLog::<[u8]>::from_datasketches_bytes(&list).unwrap();
+		let m = match &hll.m {
+			Registers::Sparse(sparse) => sparse.to_dense(12),
+			_ => panic!("expected sparse registers"),
+		};
+		assert_eq!((m[17], m[4000]), (3, 1));
+		assert_eq!(m.iter().filter(|&&x| x != 0).count(), 2);
+		let bytes = hll.to_datasketches_bytes(HllType::Hll4).unwrap();
+		assert_eq!(bytes[..8], [2, 1, 7, 12, 3, 0x08, 2, 0x00]);
+		assert_eq!(bytes.len(), 8 + 2 * 4);
+
+		// the coupon DataSketches derives for `update(1L)`, with bit 25 of its slot set
+		let coupon = 1 << 26 | 0x2fb_f22b_u32;
+		let mut compact = vec![2, 1, 7, 12, 3, 0x08, 1, 0x08];
+		compact.extend_from_slice(&coupon.to_le_bytes());
+		let mut hll = HyperLogLog::<[u8]>::with_precision(12);
+		hll.push_datasketches(&1_u64.to_le_bytes());
+		assert_eq!(hll.to_datasketches_bytes(HllType::Hll8).unwrap(), compact);
+		let copy = HyperLogLog::<[u8]>::from_datasketches_bytes(&compact).unwrap();
+		assert_eq!(copy.to_datasketches_bytes(HllType::Hll8).unwrap(), compact);
+
+		let mut hll = HyperLogLog::<[u8]>::with_precision(12);
+		for i in 0..20_000_u64 {
+			hll.push_datasketches(&i.to_le_bytes());
+			if i == 100 {
+				let bytes = hll.to_datasketches_bytes(HllType::Hll6).unwrap();
+				assert_eq!(bytes[7], 1 | 1 << 2);
+				let copy = HyperLogLog::<[u8]>::from_datasketches_bytes(&bytes).unwrap();
+				assert_eq!(copy.to_datasketches_bytes(HllType::Hll6).unwrap(), bytes);
+			}
+		}
+		let m = hll.m.unpacked().into_owned();
+		for &hll_type in &[HllType::Hll4, HllType::Hll6, HllType::Hll8] {
+			let bytes = hll.to_datasketches_bytes(hll_type).unwrap();
+			assert_eq!(
+				bytes[..8],
+				[
+					10,
+					1,
+					7,
+					12,
+					bytes[4],
+					0x18,
+					bytes[6],
+					2 | (hll_type as u8) << 2
+				]
+			);
+			let copy = HyperLogLog::<[u8]>::from_datasketches_bytes(&bytes).unwrap();
+			assert_eq!(*copy.m.unpacked(), *m);
+			assert_eq!(copy.len(), hll.len());
+		}
+		assert!((hll.len() - 20_000.0).abs() < 1000.0);
+
+		// `HLL_4` exceptions, for registers more than 14 above the minimum
+		let mut m = vec![1; 1 << 12].into_boxed_slice();
+		m[7] = 40;
+		let mut hll = HyperLogLog::<[u8]>::with_precision(12);
+		hll.m = Registers::Dense(m.clone());
+		let bytes = hll.to_datasketches_bytes(HllType::Hll4).unwrap();
+		assert_eq!((bytes[6], bytes.len()), (1, 40 + (1 << 11) + 4));
+		let copy = HyperLogLog::<[u8]>::from_datasketches_bytes(&bytes).unwrap();
+		assert_eq!(*copy.m.unpacked(), *m);
+
+		let mut missing = bytes.clone();
+		missing.truncate(missing.len() - 4);
+		missing[36] = 0;
+		for bytes in &[
+			&[2, 1, 8, 12, 3, 0x0c, 0, 0x08][..],
+			&[2, 1, 7, 22, 3, 0x0c, 0, 0x08],
+			&list[..20],
+			&missing,
+		] {
+			assert!(matches!(
+				HyperLogLog::<[u8]>::from_datasketches_bytes(bytes),
+				Err(Error::Deserialization(_))
+			));
+		}
+		assert!(matches!(
+			HyperLogLog::<[u8]>::with_precision(22).to_datasketches_bytes(HllType::Hll8),
+			Err(Error::Incompatible(_))
+		));
+	}
+
+	#[test]
+	fn postgres() {
+		// `hll_empty()`
+		let empty = b"\x11\x8b\x7f";
+		let hll = HyperLogLog::<[u8]>::from_postgres_bytes(empty).unwrap();
+		assert_eq!(hll.len(), 0.0);
+		assert_eq!(
+			hll.to_postgres_bytes(PostgresParams::default()).unwrap(),
+			empty
+		);
+
+		// `hll_add(hll_empty(), hll_hash_integer(1))`
+		let explicit = b"\x12\x8b\x7f\x88\x95\xa3\xf5\xaf\x28\xca\xfe";
+		let hll = HyperLogLog::<[u8]>::from_postgres_bytes(explicit).unwrap();
+		let mut pushed = HyperLogLog::<[u8]>::with_precision(11);
+		pushed.push_postgres(&1_i32.to_le_bytes());
+		let sparse = b"\x13\x8b\x7f\x5f\xc1";
+		assert_eq!(
+			hll.to_postgres_bytes(PostgresParams::default()).unwrap(),
+			sparse
+		);
+		assert_eq!(
+			pushed.to_postgres_bytes(PostgresParams::default()).unwrap(),
+			sparse
+		);
+
+		let mut hll = HyperLogLog::<[u8]>::with_precision(11);
+		for i in 0..50_000_i64 {
+			hll.push_postgres(&i.to_le_bytes());
+			if i == 100 {
+				let bytes = hll.to_postgres_bytes(PostgresParams::default()).unwrap();
+				assert_eq!(bytes[0], 0x13);
+				let copy = HyperLogLog::<[u8]>::from_postgres_bytes(&bytes).unwrap();
+				assert_eq!(
+					copy.to_postgres_bytes(PostgresParams::default()).unwrap(),
+					bytes
+				);
+			}
+		}
+		assert!((hll.len() - 50_000.0).abs() < 2500.0);
+		let params = PostgresParams {
+			regwidth: 6,
+			expthresh: 1024,
+			sparseon: false,
+		};
+		let wide = PostgresParams {
+			regwidth: 8,
+			..PostgresParams::default()
+		};
+		for &params in &[PostgresParams::default(), params, wide] {
+			let bytes = hll.to_postgres_bytes(params).unwrap();
+			assert_eq!(bytes.len(), 3 + 2048 * usize::from(params.regwidth) / 8);
+			assert_eq!(bytes[0], 0x14);
+			let copy = HyperLogLog::<[u8]>::from_postgres_bytes(&bytes).unwrap();
+			assert_eq!(*copy.m.unpacked(), *hll.m.unpacked());
+		}
+		assert_eq!(hll.to_postgres_bytes(params).unwrap()[1..3], [0xab, 0x0b]);
+		assert_eq!(hll.to_postgres_bytes(wide).unwrap()[1], 0xeb);
+		let mut sparse = HyperLogLog::<[u8]>::with_precision(11);
+		for i in 0..100_i32 {
+			sparse.push_postgres(&i.to_le_bytes());
+		}
+		let bytes = sparse.to_postgres_bytes(wide).unwrap();
+		assert_eq!(bytes[0], 0x13);
+		let copy = HyperLogLog::<[u8]>::from_postgres_bytes(&bytes).unwrap();
+		assert_eq!(copy.to_postgres_bytes(wide).unwrap(), bytes);
+
+		for bytes in &[
+			&b"\x21\x8b\x7f"[..],
+			b"\x14\x8b\x7f\x00",
+			b"\x12\x8b\x7f\x00",
+		] {
+			assert!(matches!(
+				HyperLogLog::<[u8]>::from_postgres_bytes(bytes),
+				Err(Error::Deserialization(_))
+			));
+		}
+		assert!(matches!(
+			HyperLogLog::<[u8]>::from_postgres_bytes(b"\x11\x9e\x7f"),
+			Err(Error::Incompatible(_))
+		));
+		for &(regwidth, expthresh) in &[(0, -1), (9, -1), (5, 3), (5, -2)] {
+			let params = PostgresParams {
+				regwidth,
+				expthresh,
+				sparseon: true,
+			};
+			assert!(matches!(
+				hll.to_postgres_bytes(params),
+				Err(Error::InvalidParameter(_))
+			));
+		}
+	}
+
+	#[test]
+	fn push_batch() {
+		let values = (0..100_000_u32).collect::<Vec<_>>();
+		for &(p, counters, layout) in &[
+			(12, false, RegisterLayout::Byte),
+			(12, true, RegisterLayout::Byte),
+			(12, false, RegisterLayout::Packed6),
+			(18, false, RegisterLayout::Byte),
+		] {
+			let mut batched = if counters {
+				HyperLogLog::new_with_counters(0.01)
+			} else {
+				HyperLogLog::with_precision(p)
+			};
+			batched.set_register_layout(layout);
+			let mut pushed = batched.clone();
+			for chunk in values.chunks(1000) {
+				batched.push_batch(chunk);
+				for value in chunk {
+					pushed.push(value);
+				}
+				assert_eq!(batched.len(), pushed.len());
+			}
+			assert_eq!(*batched.m.unpacked(), *pushed.m.unpacked());
+			assert!(batched.counters == pushed.counters);
+		}
+	}
+
+	#[test]
+	fn len_with_bounds() {
+		let mut hll = HyperLogLog::with_precision(10);
+		let bounds = hll.len_with_bounds(0.95);
+		assert_eq!((bounds.lower, bounds.upper), (0.0, 0.0));
+		let (mut covered, mut trials) = (0, 0);
+		for i in 0..200_000_u32 {
+			hll.push(&i);
+			if i % 1000 == 999 {
+				let actual = f64::from(i + 1);
+				let bounds = hll.len_with_bounds(0.95);
+				assert_eq!(bounds.estimate, hll.len());
+				assert!(bounds.lower <= bounds.estimate && bounds.estimate <= bounds.upper);
+				let wide = hll.len_with_bounds(0.999_999);
+				assert!(wide.lower < bounds.lower && bounds.upper < wide.upper);
+				assert!(wide.lower <= actual && actual <= wide.upper);
+				covered += usize::from(bounds.lower <= actual && actual <= bounds.upper);
+				trials += 1;
+			}
+		}
+		assert!(covered * 100 >= trials * 90, "{} of {}", covered, trials);
+	}
+
+	#[test]
+	fn magnitude() {
+		let mut a = HyperLogLogMagnitude::<u32>::new(&0.01);
+		let mut b = HyperLogLogMagnitude::new(&0.01);
+		for i in 0..1000 {
+			a += &i;
+		}
+		assert!(a > b);
+		for i in 0..2000 {
+			b += &i;
+			assert_eq!(b.len(), b.0.len());
+		}
+		assert!(a < b);
+		let c = a.clone();
+		assert!(a == c && a.len() == a.0.len());
+		a.union_assign(&b);
+		assert_eq!(a.len(), a.0.len());
+		assert!(a == b && a > c);
+		a += &c;
+		assert_eq!(a.len(), a.0.len());
+		let d = Intersect::intersect([&b, &c].iter().copied()).unwrap();
+		assert_eq!(d.len(), d.0.len());
+	}
+
+	#[test]
+	fn equality() {
+		let mut a = HyperLogLog::with_precision(12);
+		let mut b = HyperLogLog::with_precision(12);
+		let mut dense = HyperLogLog::with_precision(12);
+		dense.densify();
+		assert!(a == b && a == dense);
+		assert_eq!(a.fingerprint(), dense.fingerprint());
+		for i in 0..100_u32 {
+			a.push(&i);
+			b.push(&(99 - i));
+		}
+		assert!(a == b && a != dense);
+		assert_eq!(a.fingerprint(), b.fingerprint());
+		assert_ne!(a.fingerprint(), dense.fingerprint());
+		assert!(a != HyperLogLog::with_precision(13));
+		for i in 0..100_000 {
+			a.push(&i);
+			b.push(&i);
+		}
+		b.set_register_layout(RegisterLayout::Packed6);
+		assert!(a == b);
+		assert_eq!(a.fingerprint(), b.fingerprint());
+		let set = [&a, &b, &dense].iter().copied().collect::<HashSet<_>>();
+		assert_eq!(set.len(), 2);
+		for i in 100_000.. {
+			b.push(&i);
+			if a != b {
+				break;
+			}
+		}
+		assert_ne!(a.fingerprint(), b.fingerprint());
+		// fingerprints mustn't change between versions
+		let mut hll = HyperLogLog::with_precision(12);
+		assert_eq!(hll.fingerprint(), 0x6b26_d003_0dd3_9b4b);
+		hll.push(&0_u32);
+		assert_eq!(hll.fingerprint(), 0xbf5d_7c26_dff6_98c6);
+	}
 }
@@ -0,0 +1,157 @@
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// How the deletion counters of a [`HyperLogLog`](super::HyperLogLog) created with [`new_with_counters`](super::HyperLogLog::new_with_counters) are stored.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default, Debug, Serialize, Deserialize)]
+pub enum CounterLayout {
+	/// A row of counters for every register, in one contiguous allocation.
+	#[default]
+	Dense,
+	/// Rows only for the registers that have been touched, plus a 4 byte index per register. This is smaller while most registers are still zero.
+	Sparse,
+}
+
+/// Per-register counts of the `rho`s pushed, which allow registers to be decremented again on deletion.
+///
+/// Row `r` holds the counters of register `r`, indexed by register value, so it is `66 - p` wide. They're stored contiguously in `rows`; in the sparse layout, `index[r]` is one more than the row holding register `r`, or `0` if it hasn't been touched.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Counters {
+	width: usize,
+	index: Option<Box<[u32]>>,
+	rows: Vec<u8>,
+}
+impl Counters {
+	/// Zeroed counters for the `2^p` registers of precision `p`.
+	pub fn new(p: u8, layout: CounterLayout) -> Self {
+		let width = usize::from(66 - p);
+		match layout {
+			CounterLayout::Dense => Self {
+				width,
+				index: None,
+				rows: vec![0; width << p],
+			},
+			CounterLayout::Sparse => Self {
+				width,
+				index: Some(vec![0; 1 << p].into_boxed_slice()),
+				rows: Vec::new(),
+			},
+		}
+	}
+
+	pub fn layout(&self) -> CounterLayout {
+		if self.index.is_some() {
+			CounterLayout::Sparse
+		} else {
+			CounterLayout::Dense
+		}
+	}
+
+	/// The number of registers.
+	pub fn len(&self) -> usize {
+		match &self.index {
+			Some(index) => index.len(),
+			None => self.rows.len() / self.width,
+		}
+	}
+
+	/// Whether the counters have the right shape for precision `p`.
+	pub fn is_valid(&self, p: u8) -> bool {
+		let rows = self.rows.len() / self.width.max(1);
+		self.width == usize::from(66 - p)
+			&& self.rows.len().is_multiple_of(self.width)
+			&& match &self.index {
+				None => rows == 1 << p,
+				Some(index) => {
+					index.len() == 1 << p
+						&& index
+							.iter()
+							.all(|&row| usize::try_from(row).unwrap() <= rows)
+				}
+			}
+	}
+
+	/// The counters of `register`, or `None` if it has never been touched.
+	#[inline]
+	pub fn row(&self, register: usize) -> Option<&[u8]> {
+		let row = match &self.index {
+			None => register,
+			Some(index) => usize::try_from(index[register]).unwrap().checked_sub(1)?,
+		};
+		Some(&self.rows[row * self.width..(row + 1) * self.width])
+	}
+
+	/// The counters of `register`, allocating them if it has never been touched.
+	#[inline]
+	pub fn row_mut(&mut self, register: usize) -> &mut [u8] {
+		let row = match &mut self.index {
+			None => register,
+			Some(index) => {
+				if index[register] == 0 {
+					let rows = self.rows.len() / self.width;
+					self.rows.resize(self.rows.len() + self.width, 0);
+					index[register] = u32::try_from(rows + 1).unwrap();
+				}
+				usize::try_from(index[register]).unwrap() - 1
+			}
+		};
+		&mut self.rows[row * self.width..(row + 1) * self.width]
+	}
+
+	/// Convert to `layout`, if not already.
+	pub fn set_layout(&mut self, layout: CounterLayout) {
+		if self.layout() == layout {
+			return;
+		}
+		let p = u8::try_from(self.len().trailing_zeros()).unwrap();
+		let mut ret = Self::new(p, layout);
+		for register in 0..self.len() {
+			if let Some(row) = self.row(register) {
+				if row.iter().any(|&counter| counter != 0) {
+					ret.row_mut(register).copy_from_slice(row);
+				}
+			}
+		}
+		*self = ret;
+	}
+
+	/// Zero all counters, keeping the layout.
+	pub fn clear(&mut self) {
+		match &mut self.index {
+			None => self.rows.iter_mut().for_each(|counter| *counter = 0),
+			Some(index) => {
+				index.iter_mut().for_each(|row| *row = 0);
+				self.rows.clear();
+			}
+		}
+	}
+}
+impl PartialEq for Counters {
+	fn eq(&self, other: &Self) -> bool {
+		let is_zero = |row: Option<&[u8]>| row.unwrap_or(&[]).iter().all(|&x| x == 0);
+		self.width == other.width
+			&& self.len() == other.len()
+			&& (0..self.len()).all(|register| match (self.row(register), other.row(register)) {
+				(Some(a), Some(b)) => a == b,
+				(a, b) => is_zero(a) && is_zero(b),
+			})
+	}
+}
+
+/// The [SplitMix64](https://prng.di.unimi.it/splitmix64.c) generator, used for the probabilistic increments and decrements of counters above 128.
+///
+/// It's stored in the [`HyperLogLog`](super::HyperLogLog) and serialized with it, so that replaying the same operations on a sketch with the same seed always produces the same state.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default, Debug, Serialize, Deserialize)]
+pub struct SplitMix64(u64);
+impl SplitMix64 {
+	pub fn with_seed(seed: u64) -> Self {
+		Self(seed)
+	}
+
+	pub fn next_u64(&mut self) -> u64 {
+		self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		let mut z = self.0;
+		z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		z ^ (z >> 31)
+	}
+}
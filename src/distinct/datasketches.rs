@@ -0,0 +1,335 @@
+use std::convert::{TryFrom, TryInto};
+
+use super::{packed::Packed6, sparse::P_SPARSE};
+use crate::{error::Error, hash::murmur3_x64_128};
+
+/// The register array format of an [Apache DataSketches](https://datasketches.apache.org/docs/HLL/HLL.html) HLL sketch, its `TgtHllType`.
+///
+/// This only affects the size of the serialized image; sketches of any type can be read, and hold the same registers.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum HllType {
+	/// 4 bits per register, as an offset from the smallest register, with the rare registers that don't fit stored separately.
+	Hll4,
+	/// 6 bits per register.
+	Hll6,
+	/// A byte per register.
+	Hll8,
+}
+
+pub const MIN_LG_K: u8 = 4;
+pub const MAX_LG_K: u8 = 21;
+const SEED: u64 = 9001;
+const SER_VER: u8 = 1;
+const FAMILY_ID: u8 = 7;
+
+const EMPTY: u8 = 4;
+const COMPACT: u8 = 8;
+const OUT_OF_ORDER: u8 = 16;
+
+const LIST: u8 = 0;
+const SET: u8 = 1;
+const HLL: u8 = 2;
+const LIST_PRE_INTS: u8 = 2;
+const SET_PRE_INTS: u8 = 3;
+const HLL_PRE_INTS: u8 = 10;
+const LIST_START: usize = 8;
+const SET_START: usize = 12;
+const HLL_START: usize = 40;
+
+const LG_LIST_INTS: u8 = 3;
+const LG_INIT_SET_INTS: u8 = 5;
+const KEY_BITS: u32 = 26;
+const KEY_MASK: u32 = (1 << KEY_BITS) - 1;
+const AUX_TOKEN: u8 = 15;
+/// The initial `log2` size of the `HLL_4` exception table, by `lgK`.
+const LG_AUX_ARR_INTS: [u8; 22] = [
+	0, 2, 2, 2, 2, 2, 2, 3, 3, 3, 4, 4, 5, 5, 6, 7, 8, 9, 10, 11, 12, 13,
+];
+
+/// The contents of a DataSketches image: coupons in the list and set modes, registers in the HLL mode.
+pub enum Image {
+	Coupons(Vec<u32>),
+	Registers(Box<[u8]>),
+}
+
+/// Hash `element` as `HllSketch::update` does, arranged for the `HyperLogLog`.
+///
+/// DataSketches takes the slot from the low bits of `h1` and the register value from the leading zeros of `h2`. These are arranged so that both the sparse representation and the dense registers at any precision see the same slot and value.
+pub fn hash(element: &[u8]) -> u64 {
+	let (h1, h2) = murmur3_x64_128(element, SEED);
+	h2 >> P_SPARSE << P_SPARSE | h1 & ((1 << P_SPARSE) - 1)
+}
+
+/// A coupon, the 26 bit slot and 6 bit value of a hashed element.
+pub fn coupon(index: u32, rho: u8) -> u32 {
+	u32::from(rho) << KEY_BITS | index & KEY_MASK
+}
+
+/// A hash that the `HyperLogLog` registers the same way as `coupon`.
+pub fn coupon_hash(coupon: u32) -> u64 {
+	let index = u64::from(coupon & ((1 << P_SPARSE) - 1));
+	let width = 64 - P_SPARSE;
+	let rho = u8::try_from(coupon >> KEY_BITS).unwrap();
+	let w = if rho <= width { 1 << (width - rho) } else { 0 };
+	w << P_SPARSE | index
+}
+
+/// The most coupons that a sketch of `lg_k` keeps before switching to the HLL mode.
+pub fn max_coupons(lg_k: u8) -> usize {
+	if lg_k < 8 {
+		(1 << LG_LIST_INTS) - 1
+	} else {
+		3 << (lg_k - 5)
+	}
+}
+
+/// Encode coupons as a compact image in the list mode, or the set mode if there are too many for a list.
+pub fn encode_coupons(lg_k: u8, hll_type: HllType, coupons: &[u32]) -> Vec<u8> {
+	assert!(coupons.len() <= max_coupons(lg_k));
+	let hll_type = hll_type as u8;
+	let mut ret = if coupons.len() < 1 << LG_LIST_INTS {
+		let flags = if coupons.is_empty() {
+			EMPTY | COMPACT
+		} else {
+			COMPACT
+		};
+		let count = u8::try_from(coupons.len()).unwrap();
+		vec![
+			LIST_PRE_INTS,
+			SER_VER,
+			FAMILY_ID,
+			lg_k,
+			LG_LIST_INTS,
+			flags,
+			count,
+			LIST | hll_type << 2,
+		]
+	} else {
+		let mut lg_arr = LG_INIT_SET_INTS;
+		while 4 * coupons.len() > 3 << lg_arr {
+			lg_arr += 1;
+		}
+		let mut ret = vec![
+			SET_PRE_INTS,
+			SER_VER,
+			FAMILY_ID,
+			lg_k,
+			lg_arr,
+			COMPACT,
+			0,
+			SET | hll_type << 2,
+		];
+		ret.extend_from_slice(&u32::try_from(coupons.len()).unwrap().to_le_bytes());
+		ret
+	};
+	for coupon in coupons {
+		ret.extend_from_slice(&coupon.to_le_bytes());
+	}
+	ret
+}
+
+/// Encode the registers `m` as a compact image in the HLL mode.
+///
+/// The sketch is flagged as out of order, as it would be after a union, so readers estimate from the registers rather than the HIP accumulator, which is set to `estimate`.
+pub fn encode_registers(hll_type: HllType, m: &[u8], estimate: f64) -> Vec<u8> {
+	let lg_k = u8::try_from(m.len().trailing_zeros()).unwrap();
+	let cur_min = match hll_type {
+		HllType::Hll4 => m.iter().copied().min().unwrap(),
+		HllType::Hll6 | HllType::Hll8 => 0,
+	};
+	let (mut kxq0, mut kxq1) = (0.0, 0.0);
+	let mut num_at_cur_min = 0;
+	for &x in m {
+		num_at_cur_min += usize::from(x == cur_min);
+		if x < 32 {
+			kxq0 += 0.5_f64.powi(i32::from(x));
+		} else {
+			kxq1 += 0.5_f64.powi(i32::from(x));
+		}
+	}
+	let mut aux = Vec::new();
+	let registers = match hll_type {
+		HllType::Hll4 => {
+			let mut nibbles = vec![0; m.len() / 2];
+			for (slot, &x) in m.iter().enumerate() {
+				let nibble = if x - cur_min < AUX_TOKEN {
+					x - cur_min
+				} else {
+					aux.push(coupon(u32::try_from(slot).unwrap(), x));
+					AUX_TOKEN
+				};
+				nibbles[slot / 2] |= nibble << (slot % 2 * 4);
+			}
+			nibbles
+		}
+		HllType::Hll6 => Packed6::from_registers(m).as_bytes().to_vec(),
+		HllType::Hll8 => m.to_vec(),
+	};
+	let lg_arr = if hll_type == HllType::Hll4 {
+		let mut lg_arr = LG_AUX_ARR_INTS[usize::from(lg_k)];
+		while 4 * aux.len() > 3 << lg_arr {
+			lg_arr += 1;
+		}
+		lg_arr
+	} else {
+		0
+	};
+	let mut ret = vec![
+		HLL_PRE_INTS,
+		SER_VER,
+		FAMILY_ID,
+		lg_k,
+		lg_arr,
+		COMPACT | OUT_OF_ORDER,
+		cur_min,
+		HLL | (hll_type as u8) << 2,
+	];
+	ret.extend_from_slice(&estimate.to_le_bytes());
+	ret.extend_from_slice(&kxq0.to_le_bytes());
+	ret.extend_from_slice(&kxq1.to_le_bytes());
+	ret.extend_from_slice(&u32::try_from(num_at_cur_min).unwrap().to_le_bytes());
+	ret.extend_from_slice(&u32::try_from(aux.len()).unwrap().to_le_bytes());
+	ret.extend_from_slice(&registers);
+	for coupon in aux {
+		ret.extend_from_slice(&coupon.to_le_bytes());
+	}
+	ret
+}
+
+/// Decode an image in any mode, compact or updatable, returning `lgK` and its contents.
+pub fn decode(bytes: &[u8]) -> Result<(u8, Image), Error> {
+	if bytes.len() < LIST_START {
+		return Err(Error::Deserialization("DataSketches HLL image truncated"));
+	}
+	let (pre_ints, lg_k, lg_arr, flags, mode) = (bytes[0], bytes[3], bytes[4], bytes[5], bytes[7]);
+	if bytes[1] != SER_VER || bytes[2] != FAMILY_ID {
+		return Err(Error::Deserialization("not a DataSketches HLL sketch"));
+	}
+	if !(MIN_LG_K..=MAX_LG_K).contains(&lg_k) {
+		return Err(Error::Deserialization("DataSketches HLL lgK out of range"));
+	}
+	let hll_type = match mode >> 2 & 3 {
+		0 => HllType::Hll4,
+		1 => HllType::Hll6,
+		2 => HllType::Hll8,
+		_ => return Err(Error::Deserialization("unknown DataSketches HLL type")),
+	};
+	let compact = flags & COMPACT != 0;
+	let image = match (mode & 3, pre_ints) {
+		_ if flags & EMPTY != 0 => Image::Coupons(Vec::new()),
+		(LIST, LIST_PRE_INTS) => {
+			let count = if compact {
+				usize::from(bytes[6])
+			} else {
+				table_len(lg_arr)?
+			};
+			Image::Coupons(read_coupons(&bytes[LIST_START..], count)?)
+		}
+		(SET, SET_PRE_INTS) => {
+			let count = read_u32(bytes, LIST_START)?;
+			let count = if compact { count } else { table_len(lg_arr)? };
+			Image::Coupons(read_coupons(&bytes[SET_START..], count)?)
+		}
+		(HLL, HLL_PRE_INTS) => {
+			Image::Registers(decode_registers(bytes, lg_k, lg_arr, compact, hll_type)?)
+		}
+		_ => return Err(Error::Deserialization("unknown DataSketches HLL mode")),
+	};
+	Ok((lg_k, image))
+}
+
+fn decode_registers(
+	bytes: &[u8], lg_k: u8, lg_arr: u8, compact: bool, hll_type: HllType,
+) -> Result<Box<[u8]>, Error> {
+	let k = 1 << lg_k;
+	let cur_min = bytes[6];
+	if cur_min > 63 {
+		return Err(Error::Deserialization(
+			"DataSketches HLL register out of range",
+		));
+	}
+	let aux_count = read_u32(bytes, 36)?;
+	let body = &bytes[HLL_START..];
+	let truncated = Error::Deserialization("DataSketches HLL image truncated");
+	let m: Box<[u8]> = match hll_type {
+		HllType::Hll4 => {
+			let nibbles = body.get(..k / 2).ok_or(truncated)?;
+			let aux = if aux_count == 0 {
+				Vec::new()
+			} else {
+				let count = if compact {
+					aux_count
+				} else {
+					table_len(lg_arr)?
+				};
+				read_coupons(&body[k / 2..], count)?
+			};
+			// registers holding `AUX_TOKEN` are marked with `u8::MAX` until their exception is found
+			let mut m = (0..k)
+				.map(|slot| match nibbles[slot / 2] >> (slot % 2 * 4) & 0xf {
+					AUX_TOKEN => u8::MAX,
+					nibble => nibble + cur_min,
+				})
+				.collect::<Box<[u8]>>();
+			for coupon in aux {
+				let slot = usize::try_from(coupon & KEY_MASK).unwrap();
+				match m.get_mut(slot) {
+					Some(x) if *x == u8::MAX => *x = u8::try_from(coupon >> KEY_BITS).unwrap(),
+					_ => {
+						return Err(Error::Deserialization(
+							"DataSketches HLL_4 exception invalid",
+						))
+					}
+				}
+			}
+			m
+		}
+		HllType::Hll6 => {
+			let bytes_len = Packed6::bytes_len(k);
+			Packed6::from_bytes(k, body.get(..bytes_len).ok_or(truncated)?)
+				.unwrap()
+				.to_registers()
+		}
+		HllType::Hll8 => body.get(..k).ok_or(truncated)?.into(),
+	};
+	if m.iter().any(|&x| x > 63) {
+		return Err(Error::Deserialization(
+			"DataSketches HLL register out of range",
+		));
+	}
+	Ok(m)
+}
+
+/// The number of ints in a hash table of `log2` size `lg_arr`.
+fn table_len(lg_arr: u8) -> Result<usize, Error> {
+	if u32::from(lg_arr) > KEY_BITS {
+		return Err(Error::Deserialization(
+			"DataSketches HLL lgArr out of range",
+		));
+	}
+	Ok(1 << lg_arr)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<usize, Error> {
+	let int = bytes
+		.get(offset..offset + 4)
+		.ok_or(Error::Deserialization("DataSketches HLL image truncated"))?;
+	Ok(usize::try_from(u32::from_le_bytes(int.try_into().unwrap())).unwrap())
+}
+
+/// Read `count` coupons, skipping the empty entries of updatable hash tables.
+fn read_coupons(body: &[u8], count: usize) -> Result<Vec<u32>, Error> {
+	let ints = body
+		.get(..count.saturating_mul(4))
+		.ok_or(Error::Deserialization("DataSketches HLL image truncated"))?;
+	ints.chunks_exact(4)
+		.map(|int| u32::from_le_bytes(int.try_into().unwrap()))
+		.filter(|&coupon| coupon != 0)
+		.map(|coupon| {
+			if coupon >> KEY_BITS == 0 {
+				return Err(Error::Deserialization("DataSketches HLL coupon invalid"));
+			}
+			Ok(coupon)
+		})
+		.collect()
+}
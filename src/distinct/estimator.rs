@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, f64};
+
+use crate::usize_to_f64;
+
+/// The algorithm used by [`HyperLogLog::len`](super::HyperLogLog::len) to estimate the cardinality from the registers.
+///
+/// See [*New cardinality estimation algorithms for HyperLogLog sketches*](https://arxiv.org/abs/1702.01284) by Otmar Ertl for background on the estimators that work from the register histogram.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default, Debug, Serialize, Deserialize)]
+pub enum Estimator {
+	/// Linear counting for small cardinalities, then the raw estimate corrected with the empirical bias data from the HyperLogLog++ paper. Above a precision of 18, where there is no bias data, this uses [`Improved`](Estimator::Improved).
+	///
+	/// This is O(1) for dense registers, as the sums it needs are maintained as elements are pushed.
+	#[default]
+	HyperLogLogPlusPlus,
+	/// Ertl's improved raw estimator, which corrects the raw estimate for both small and large cardinalities without any empirical data.
+	Improved,
+	/// Ertl's maximum-likelihood estimator, which is slightly more accurate than [`Improved`](Estimator::Improved) but iterative.
+	MaximumLikelihood,
+}
+
+/// Ertl's improved raw estimator, given the histogram `c` of register values `0..=q+1`.
+pub fn improved(c: &[usize]) -> f64 {
+	let q = c.len() - 2;
+	let m = usize_to_f64(c.iter().sum());
+	let mut z = m * tau(1.0 - usize_to_f64(c[q + 1]) / m);
+	for &c_k in c[1..=q].iter().rev() {
+		z = 0.5 * (z + usize_to_f64(c_k));
+	}
+	z += m * sigma(usize_to_f64(c[0]) / m);
+	m * m / (2.0 * f64::consts::LN_2 * z)
+}
+
+fn sigma(mut x: f64) -> f64 {
+	if x == 1.0 {
+		return f64::INFINITY;
+	}
+	let mut y = 1.0;
+	let mut z = x;
+	loop {
+		x *= x;
+		let z_old = z;
+		z += x * y;
+		y += y;
+		if z == z_old {
+			return z;
+		}
+	}
+}
+
+fn tau(mut x: f64) -> f64 {
+	if x == 0.0 || x == 1.0 {
+		return 0.0;
+	}
+	let mut y = 1.0;
+	let mut z = 1.0 - x;
+	loop {
+		x = x.sqrt();
+		let z_old = z;
+		y *= 0.5;
+		z -= (1.0 - x).powi(2) * y;
+		if z == z_old {
+			return z / 3.0;
+		}
+	}
+}
+
+/// Ertl's maximum-likelihood estimator, given the histogram `c` of register values `0..=q+1`.
+///
+/// Under the Poissonization model the derivative of the log-likelihood with respect to `x = λ/m` is `f(x) - a`, where `f(x) = Σ c[k] 2^-k / (e^(x 2^-k) - 1)` summed over the non-zero register values (with the saturated value `q+1` weighted as `q`), and `a = c[0] + Σ c[k] 2^-k` over `1..=q`. `f` is convex and decreasing, so Newton's method started to the left of the root converges monotonically to it.
+pub fn maximum_likelihood(c: &[usize]) -> f64 {
+	let q = c.len() - 2;
+	let m = usize_to_f64(c.iter().sum());
+	let weight = |k: usize| 0.5_f64.powi(i32::try_from(k.min(q)).unwrap());
+	let target = usize_to_f64(c[0]) + (1..=q).map(|k| usize_to_f64(c[k]) * weight(k)).sum::<f64>();
+	if usize_to_f64(c[0]) == m {
+		return 0.0;
+	}
+	if target == 0.0 {
+		return f64::INFINITY;
+	}
+	// 1/(e^y - 1) > 1/y - 1/2, so f(x) > a for x <= (m - c[0]) / (a + slack)
+	let slack = (1..=q + 1)
+		.map(|k| usize_to_f64(c[k]) * weight(k))
+		.sum::<f64>()
+		/ 2.0;
+	let mut x = (m - usize_to_f64(c[0])) / (target + slack);
+	for _ in 0..100 {
+		let (mut f_x, mut df_x) = (0.0, 0.0);
+		for (k, &c_k) in c.iter().enumerate().skip(1) {
+			if c_k == 0 {
+				continue;
+			}
+			let (c_k, weight_k) = (usize_to_f64(c_k), weight(k));
+			let exp_m1 = (x * weight_k).exp_m1();
+			f_x += c_k * weight_k / exp_m1;
+			df_x -= c_k * weight_k * weight_k * (exp_m1 + 1.0) / (exp_m1 * exp_m1);
+		}
+		let step = (target - f_x) / df_x;
+		if step.is_nan() || step <= x * 1e-12 {
+			break;
+		}
+		x += step;
+	}
+	m * x
+}
@@ -0,0 +1,310 @@
+/// How to combine a pair of registers.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Merge {
+	/// Take the maximum, for a union.
+	Max,
+	/// Take the minimum, for an intersection.
+	Min,
+}
+
+/// Merge `src` into `m` with `op`, returning the new count of zero registers and sum of 2<sup>-register</sup>.
+///
+/// This uses the widest SIMD instructions the CPU supports, detected at runtime, and scalar code for the tail that doesn't fill a vector.
+pub fn merge(m: &mut [u8], src: &[u8], op: Merge) -> (usize, f64) {
+	assert_eq!(m.len(), src.len());
+	#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+	{
+		if is_x86_feature_detected!("avx2") {
+			return unsafe { x86::merge_avx2(m, src, op) };
+		}
+		if is_x86_feature_detected!("sse2") {
+			return unsafe { x86::merge_sse2(m, src, op) };
+		}
+	}
+	#[cfg(target_arch = "aarch64")]
+	{
+		if std::arch::is_aarch64_feature_detected!("neon") {
+			return unsafe { aarch64::merge_neon(m, src, op) };
+		}
+	}
+	merge_scalar(m, src, op)
+}
+
+/// The count of zero registers and sum of 2<sup>-register</sup> of `m`.
+pub fn recount(m: &[u8]) -> (usize, f64) {
+	#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+	{
+		if is_x86_feature_detected!("avx2") {
+			return unsafe { x86::recount_avx2(m) };
+		}
+		if is_x86_feature_detected!("sse2") {
+			return unsafe { x86::recount_sse2(m) };
+		}
+	}
+	#[cfg(target_arch = "aarch64")]
+	{
+		if std::arch::is_aarch64_feature_detected!("neon") {
+			return unsafe { aarch64::recount_neon(m) };
+		}
+	}
+	recount_scalar(m)
+}
+
+pub fn merge_scalar(m: &mut [u8], src: &[u8], op: Merge) -> (usize, f64) {
+	for (to, &from) in m.iter_mut().zip(src) {
+		*to = match op {
+			Merge::Max => (*to).max(from),
+			Merge::Min => (*to).min(from),
+		};
+	}
+	recount_scalar(m)
+}
+
+pub fn recount_scalar(m: &[u8]) -> (usize, f64) {
+	let mut zero = 0;
+	let mut sum = 0.0;
+	for &x in m {
+		zero += usize::from(x == 0);
+		// see pow_bithack()
+		sum += f64::from_bits(u64::MAX.wrapping_sub(u64::from(x)) << 54 >> 2);
+	}
+	(zero, sum)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+	#[cfg(target_arch = "x86")]
+	use std::arch::x86 as arch;
+	#[cfg(target_arch = "x86_64")]
+	use std::arch::x86_64 as arch;
+
+	use arch::{
+		__m128d, __m128i, __m256d, __m256i, _mm256_add_pd, _mm256_castsi256_pd, _mm256_cmpeq_epi8, _mm256_cvtepu8_epi64, _mm256_loadu_si256, _mm256_max_epu8, _mm256_min_epu8, _mm256_movemask_epi8, _mm256_set1_epi64x, _mm256_setzero_pd, _mm256_setzero_si256, _mm256_slli_epi64, _mm256_storeu_pd, _mm256_storeu_si256, _mm256_sub_epi64, _mm_add_pd, _mm_castsi128_pd, _mm_cmpeq_epi8, _mm_cvtsi32_si128, _mm_loadu_si128, _mm_max_epu8, _mm_min_epu8, _mm_movemask_epi8, _mm_set1_epi64x, _mm_setzero_pd, _mm_setzero_si128, _mm_slli_epi64, _mm_storeu_pd, _mm_storeu_si128, _mm_sub_epi64, _mm_unpackhi_epi16, _mm_unpackhi_epi32, _mm_unpackhi_epi8, _mm_unpacklo_epi16, _mm_unpacklo_epi32, _mm_unpacklo_epi8
+	};
+
+	use std::convert::TryFrom;
+
+	use super::{merge_scalar, recount_scalar, Merge};
+
+	// 2^-x is the f64 with biased exponent 1023 - x and a zero mantissa, as registers never exceed 64
+
+	#[target_feature(enable = "avx2")]
+	pub unsafe fn merge_avx2(m: &mut [u8], src: &[u8], op: Merge) -> (usize, f64) {
+		let len = m.len() / 32 * 32;
+		let (mut zero, mut sum) = (0, _mm256_setzero_pd());
+		for i in (0..len).step_by(32) {
+			let ptr = m.as_mut_ptr().add(i);
+			let a = _mm256_loadu_si256(ptr.cast());
+			let b = _mm256_loadu_si256(src.as_ptr().add(i).cast());
+			let x = match op {
+				Merge::Max => _mm256_max_epu8(a, b),
+				Merge::Min => _mm256_min_epu8(a, b),
+			};
+			_mm256_storeu_si256(ptr.cast(), x);
+			zero += count_avx2(x, ptr, &mut sum);
+		}
+		let (tail_zero, tail_sum) = merge_scalar(&mut m[len..], &src[len..], op);
+		(zero + tail_zero, sum_avx2(sum) + tail_sum)
+	}
+
+	#[target_feature(enable = "avx2")]
+	pub unsafe fn recount_avx2(m: &[u8]) -> (usize, f64) {
+		let len = m.len() / 32 * 32;
+		let (mut zero, mut sum) = (0, _mm256_setzero_pd());
+		for i in (0..len).step_by(32) {
+			let ptr = m.as_ptr().add(i);
+			zero += count_avx2(_mm256_loadu_si256(ptr.cast()), ptr, &mut sum);
+		}
+		let (tail_zero, tail_sum) = recount_scalar(&m[len..]);
+		(zero + tail_zero, sum_avx2(sum) + tail_sum)
+	}
+
+	/// Count the zero registers of `x`, and add 2<sup>-register</sup> to `sum`, given that `x` was loaded from `ptr`.
+	#[inline]
+	#[target_feature(enable = "avx2")]
+	unsafe fn count_avx2(x: __m256i, ptr: *const u8, sum: &mut __m256d) -> usize {
+		let zeros = _mm256_movemask_epi8(_mm256_cmpeq_epi8(x, _mm256_setzero_si256()));
+		let bias = _mm256_set1_epi64x(1023);
+		for j in (0..32).step_by(4) {
+			let x =
+				_mm256_cvtepu8_epi64(_mm_cvtsi32_si128(ptr.add(j).cast::<i32>().read_unaligned()));
+			let pow = _mm256_castsi256_pd(_mm256_slli_epi64(_mm256_sub_epi64(bias, x), 52));
+			*sum = _mm256_add_pd(*sum, pow);
+		}
+		usize::try_from(zeros.count_ones()).unwrap()
+	}
+
+	#[inline]
+	#[target_feature(enable = "avx2")]
+	unsafe fn sum_avx2(sum: __m256d) -> f64 {
+		let mut lanes = [0.0; 4];
+		_mm256_storeu_pd(lanes.as_mut_ptr(), sum);
+		lanes.iter().sum()
+	}
+
+	#[target_feature(enable = "sse2")]
+	pub unsafe fn merge_sse2(m: &mut [u8], src: &[u8], op: Merge) -> (usize, f64) {
+		let len = m.len() / 16 * 16;
+		let (mut zero, mut sum) = (0, _mm_setzero_pd());
+		for i in (0..len).step_by(16) {
+			let ptr = m.as_mut_ptr().add(i);
+			let a = _mm_loadu_si128(ptr.cast());
+			let b = _mm_loadu_si128(src.as_ptr().add(i).cast());
+			let x = match op {
+				Merge::Max => _mm_max_epu8(a, b),
+				Merge::Min => _mm_min_epu8(a, b),
+			};
+			_mm_storeu_si128(ptr.cast(), x);
+			zero += count_sse2(x, &mut sum);
+		}
+		let (tail_zero, tail_sum) = merge_scalar(&mut m[len..], &src[len..], op);
+		(zero + tail_zero, sum_sse2(sum) + tail_sum)
+	}
+
+	#[target_feature(enable = "sse2")]
+	pub unsafe fn recount_sse2(m: &[u8]) -> (usize, f64) {
+		let len = m.len() / 16 * 16;
+		let (mut zero, mut sum) = (0, _mm_setzero_pd());
+		for i in (0..len).step_by(16) {
+			zero += count_sse2(_mm_loadu_si128(m.as_ptr().add(i).cast()), &mut sum);
+		}
+		let (tail_zero, tail_sum) = recount_scalar(&m[len..]);
+		(zero + tail_zero, sum_sse2(sum) + tail_sum)
+	}
+
+	/// Count the zero registers of `x`, and add 2<sup>-register</sup> to `sum`, widening the registers to 64 bits by interleaving with zeros.
+	#[inline]
+	#[target_feature(enable = "sse2")]
+	unsafe fn count_sse2(x: __m128i, sum: &mut __m128d) -> usize {
+		let z = _mm_setzero_si128();
+		let zeros = _mm_movemask_epi8(_mm_cmpeq_epi8(x, z));
+		let bias = _mm_set1_epi64x(1023);
+		for &x in &[_mm_unpacklo_epi8(x, z), _mm_unpackhi_epi8(x, z)] {
+			for &x in &[_mm_unpacklo_epi16(x, z), _mm_unpackhi_epi16(x, z)] {
+				for &x in &[_mm_unpacklo_epi32(x, z), _mm_unpackhi_epi32(x, z)] {
+					let pow = _mm_castsi128_pd(_mm_slli_epi64(_mm_sub_epi64(bias, x), 52));
+					*sum = _mm_add_pd(*sum, pow);
+				}
+			}
+		}
+		usize::try_from(zeros.count_ones()).unwrap()
+	}
+
+	#[inline]
+	#[target_feature(enable = "sse2")]
+	unsafe fn sum_sse2(sum: __m128d) -> f64 {
+		let mut lanes = [0.0; 2];
+		_mm_storeu_pd(lanes.as_mut_ptr(), sum);
+		lanes[0] + lanes[1]
+	}
+}
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64 {
+	use std::arch::aarch64::{
+		float64x2_t, uint8x16_t, vaddq_f64, vaddvq_f64, vaddvq_u8, vandq_u8, vceqq_u8, vdupq_n_f64, vdupq_n_s64, vdupq_n_u64, vdupq_n_u8, vget_low_u16, vget_low_u32, vget_low_u8, vld1q_u8, vmaxq_u8, vminq_u8, vmovl_high_u16, vmovl_high_u32, vmovl_high_u8, vmovl_u16, vmovl_u32, vmovl_u8, vreinterpretq_f64_u64, vshlq_u64, vst1q_u8, vsubq_u64
+	};
+
+	use super::{merge_scalar, recount_scalar, Merge};
+
+	#[target_feature(enable = "neon")]
+	pub unsafe fn merge_neon(m: &mut [u8], src: &[u8], op: Merge) -> (usize, f64) {
+		let len = m.len() / 16 * 16;
+		let (mut zero, mut sum) = (0, vdupq_n_f64(0.0));
+		for i in (0..len).step_by(16) {
+			let ptr = m.as_mut_ptr().add(i);
+			let a = vld1q_u8(ptr);
+			let b = vld1q_u8(src.as_ptr().add(i));
+			let x = match op {
+				Merge::Max => vmaxq_u8(a, b),
+				Merge::Min => vminq_u8(a, b),
+			};
+			vst1q_u8(ptr, x);
+			zero += count_neon(x, &mut sum);
+		}
+		let (tail_zero, tail_sum) = merge_scalar(&mut m[len..], &src[len..], op);
+		(zero + tail_zero, vaddvq_f64(sum) + tail_sum)
+	}
+
+	#[target_feature(enable = "neon")]
+	pub unsafe fn recount_neon(m: &[u8]) -> (usize, f64) {
+		let len = m.len() / 16 * 16;
+		let (mut zero, mut sum) = (0, vdupq_n_f64(0.0));
+		for i in (0..len).step_by(16) {
+			zero += count_neon(vld1q_u8(m.as_ptr().add(i)), &mut sum);
+		}
+		let (tail_zero, tail_sum) = recount_scalar(&m[len..]);
+		(zero + tail_zero, vaddvq_f64(sum) + tail_sum)
+	}
+
+	/// Count the zero registers of `x`, and add 2<sup>-register</sup> to `sum`, widening the registers to 64 bits.
+	#[inline]
+	#[target_feature(enable = "neon")]
+	unsafe fn count_neon(x: uint8x16_t, sum: &mut float64x2_t) -> usize {
+		let zeros = vaddvq_u8(vandq_u8(vceqq_u8(x, vdupq_n_u8(0)), vdupq_n_u8(1)));
+		let (bias, shift) = (vdupq_n_u64(1023), vdupq_n_s64(52));
+		for &x in &[vmovl_u8(vget_low_u8(x)), vmovl_high_u8(x)] {
+			for &x in &[vmovl_u16(vget_low_u16(x)), vmovl_high_u16(x)] {
+				for &x in &[vmovl_u32(vget_low_u32(x)), vmovl_high_u32(x)] {
+					let pow = vreinterpretq_f64_u64(vshlq_u64(vsubq_u64(bias, x), shift));
+					*sum = vaddq_f64(*sum, pow);
+				}
+			}
+		}
+		usize::from(zeros)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	#[cfg(target_arch = "aarch64")]
+	use super::aarch64;
+	#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+	use super::x86;
+	use super::{merge, merge_scalar, recount, recount_scalar, Merge};
+	use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+	/// The zero counts must match exactly, while the sums may differ in rounding as the kernels add in a different order.
+	#[track_caller]
+	fn assert_counts(a: (usize, f64), b: (usize, f64)) {
+		assert_eq!(a.0, b.0);
+		assert!((a.1 - b.1).abs() <= b.1 * 1e-12, "{} != {}", a.1, b.1);
+	}
+
+	#[test]
+	fn kernels() {
+		let mut rng = SmallRng::seed_from_u64(0);
+		// lengths that exercise the vector loops, the scalar tail, and both, with every value a register can hold
+		for &len in &[0, 1, 15, 16, 17, 31, 32, 33, 100, 1 << 12] {
+			let m = (0..len).map(|_| rng.gen_range(0, 64)).collect::<Vec<u8>>();
+			let src = (0..len).map(|_| rng.gen_range(0, 64)).collect::<Vec<u8>>();
+			assert_counts(recount(&m), recount_scalar(&m));
+			for &op in &[Merge::Max, Merge::Min] {
+				let mut expected = m.clone();
+				let counts = merge_scalar(&mut expected, &src, op);
+				let mut a = m.clone();
+				assert_counts(merge(&mut a, &src, op), counts);
+				assert_eq!(a, expected);
+				// check each kernel explicitly, as `merge` only picks the widest available
+				#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+				{
+					if is_x86_feature_detected!("sse2") {
+						let mut a = m.clone();
+						assert_counts(unsafe { x86::merge_sse2(&mut a, &src, op) }, counts);
+						assert_eq!(a, expected);
+						assert_counts(unsafe { x86::recount_sse2(&m) }, recount_scalar(&m));
+					}
+				}
+				#[cfg(target_arch = "aarch64")]
+				{
+					if std::arch::is_aarch64_feature_detected!("neon") {
+						let mut a = m.clone();
+						assert_counts(unsafe { aarch64::merge_neon(&mut a, &src, op) }, counts);
+						assert_eq!(a, expected);
+						assert_counts(unsafe { aarch64::recount_neon(&m) }, recount_scalar(&m));
+					}
+				}
+			}
+		}
+	}
+}
@@ -0,0 +1,186 @@
+use std::convert::{TryFrom, TryInto};
+
+use super::{MAX_P, MIN_P};
+use crate::{error::Error, hash::murmur3_x64_128};
+
+/// The type modifiers of a [`postgresql-hll`](https://github.com/citusdata/postgresql-hll) column other than `log2m`, which is the precision of the [`HyperLogLog`](super::HyperLogLog).
+///
+/// `postgresql-hll` only unions values whose modifiers all match, so these should be those of the column being written to. The default is that of an unmodified `hll` column.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct PostgresParams {
+	/// The number of bits per register, `1..=8`. Registers are capped at `2^regwidth - 1`.
+	pub regwidth: u8,
+	/// The number of elements held in the `EXPLICIT` representation: `-1` to choose automatically, `0` to disable it, or a power of two.
+	pub expthresh: i64,
+	/// Whether the `SPARSE` representation is enabled.
+	pub sparseon: bool,
+}
+impl Default for PostgresParams {
+	fn default() -> Self {
+		Self {
+			regwidth: 5,
+			expthresh: -1,
+			sparseon: true,
+		}
+	}
+}
+
+const SCHEMA_VERSION: u8 = 1;
+const EMPTY: u8 = 1;
+const EXPLICIT: u8 = 2;
+const SPARSE: u8 = 3;
+const FULL: u8 = 4;
+const HEADER_LEN: usize = 3;
+const EXPTHRESH_AUTO: u8 = 63;
+
+/// The contents of a `postgresql-hll` value: the raw hashes of the `EMPTY` and `EXPLICIT` representations, or the registers of the `SPARSE` and `FULL` representations.
+pub enum Image {
+	Hashes(Vec<u64>),
+	Registers(Box<[u8]>),
+}
+
+/// Hash `element` as `hll_hash_bytea` does, with MurmurHash3 and a seed of 0.
+pub fn hash(element: &[u8]) -> u64 {
+	murmur3_x64_128(element, 0).0
+}
+
+/// Arrange the raw hash `x` for the `HyperLogLog` of precision `p`, or `None` if `postgresql-hll` ignores it.
+///
+/// `postgresql-hll` takes the register index from the low `p` bits and the value from the trailing zeros of the rest, whereas the `HyperLogLog` counts leading zeros, so those bits are reversed. It ignores hashes for which the rest is zero.
+pub fn arrange(x: u64, p: u8) -> Option<u64> {
+	let w = x >> p;
+	if w == 0 {
+		return None;
+	}
+	Some(w.reverse_bits() >> p << p | x & ((1 << p) - 1))
+}
+
+/// Encode the registers `m` in the `EMPTY`, `SPARSE` or `FULL` representation, whichever is smallest.
+pub fn encode(m: &[u8], params: PostgresParams) -> Result<Vec<u8>, Error> {
+	if !(1..=8).contains(&params.regwidth) {
+		return Err(Error::InvalidParameter("regwidth must be between 1 and 8"));
+	}
+	let cutoff = match params.expthresh {
+		-1 => EXPTHRESH_AUTO,
+		0 => 0,
+		x if x > 0 && x.count_ones() == 1 && x.trailing_zeros() < 62 => {
+			u8::try_from(x.trailing_zeros()).unwrap() + 1
+		}
+		_ => {
+			return Err(Error::InvalidParameter(
+				"expthresh must be -1, 0 or a power of two",
+			))
+		}
+	};
+	let log2m = u8::try_from(m.len().trailing_zeros()).unwrap();
+	let max = u8::MAX >> (8 - params.regwidth);
+	let mut ret = vec![
+		SCHEMA_VERSION << 4 | EMPTY,
+		(params.regwidth - 1) << 5 | log2m,
+		u8::from(params.sparseon) << 6 | cutoff,
+	];
+	let set = m.iter().filter(|&&x| x != 0).count();
+	if set == 0 {
+		return Ok(ret);
+	}
+	let entry_bits = usize::from(log2m + params.regwidth);
+	let full_bits = m.len() * usize::from(params.regwidth);
+	let mut bits = BitWriter::default();
+	if params.sparseon && set * entry_bits < full_bits {
+		ret[0] = SCHEMA_VERSION << 4 | SPARSE;
+		for (index, &x) in m.iter().enumerate().filter(|&(_, &x)| x != 0) {
+			let entry = u64::try_from(index).unwrap() << params.regwidth | u64::from(x.min(max));
+			bits.write(entry, log2m + params.regwidth);
+		}
+	} else {
+		ret[0] = SCHEMA_VERSION << 4 | FULL;
+		for &x in m {
+			bits.write(u64::from(x.min(max)), params.regwidth);
+		}
+	}
+	ret.extend_from_slice(&bits.bytes);
+	Ok(ret)
+}
+
+/// Decode a value in any representation, returning `log2m` and its contents.
+pub fn decode(bytes: &[u8]) -> Result<(u8, Image), Error> {
+	if bytes.len() < HEADER_LEN || bytes[0] >> 4 != SCHEMA_VERSION {
+		return Err(Error::Deserialization("not a postgresql-hll value"));
+	}
+	let (regwidth, log2m) = (bytes[1] >> 5 & 0x7, bytes[1] & 0x1f);
+	let regwidth = regwidth + 1;
+	if !(MIN_P..=MAX_P).contains(&log2m) {
+		return Err(Error::Incompatible(
+			"postgresql-hll value has a log2m outside the supported precisions",
+		));
+	}
+	let body = &bytes[HEADER_LEN..];
+	let image = match bytes[0] & 0xf {
+		EMPTY if body.is_empty() => Image::Hashes(Vec::new()),
+		EXPLICIT if body.len().is_multiple_of(8) => Image::Hashes(
+			body.chunks_exact(8)
+				.map(|x| u64::from_be_bytes(x.try_into().unwrap()))
+				.collect(),
+		),
+		SPARSE => {
+			let entry_bits = log2m + regwidth;
+			let mut m = vec![0; 1 << log2m].into_boxed_slice();
+			for i in 0..body.len() * 8 / usize::from(entry_bits) {
+				let entry = read_bits(body, i * usize::from(entry_bits), entry_bits);
+				let index = usize::try_from(entry >> regwidth).unwrap();
+				// a zeroed entry can only be padding
+				let x = u8::try_from(entry & ((1 << regwidth) - 1)).unwrap();
+				if x != 0 {
+					m[index] = x;
+				}
+			}
+			Image::Registers(m)
+		}
+		FULL if body.len() == ((1 << log2m) * usize::from(regwidth)).div_ceil(8) => {
+			Image::Registers(
+				(0..1 << log2m)
+					.map(|i| {
+						u8::try_from(read_bits(body, i * usize::from(regwidth), regwidth)).unwrap()
+					})
+					.collect(),
+			)
+		}
+		EMPTY | EXPLICIT | FULL => {
+			return Err(Error::Deserialization(
+				"postgresql-hll value has the wrong length",
+			))
+		}
+		_ => {
+			return Err(Error::Deserialization(
+				"unknown postgresql-hll representation",
+			))
+		}
+	};
+	Ok((log2m, image))
+}
+
+/// Bits packed most significant first, as `postgresql-hll` packs its registers.
+#[derive(Default)]
+struct BitWriter {
+	bytes: Vec<u8>,
+	len: usize,
+}
+impl BitWriter {
+	fn write(&mut self, value: u64, width: u8) {
+		for i in (0..width).rev() {
+			if self.len.is_multiple_of(8) {
+				self.bytes.push(0);
+			}
+			if value >> i & 1 != 0 {
+				*self.bytes.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+			}
+			self.len += 1;
+		}
+	}
+}
+
+fn read_bits(bytes: &[u8], offset: usize, width: u8) -> u64 {
+	(offset..offset + usize::from(width)).fold(0, |value, bit| {
+		value << 1 | u64::from(bytes[bit / 8] >> (7 - bit % 8) & 1)
+	})
+}
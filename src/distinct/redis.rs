@@ -0,0 +1,141 @@
+use std::convert::TryFrom;
+
+use super::packed::Packed6;
+use crate::{error::Error, hash::murmur64a};
+
+/// The precision of every Redis HyperLogLog.
+pub const P: u8 = 14;
+const REGISTERS: usize = 1 << P;
+const SEED: u64 = 0xadc8_3b19;
+const MAGIC: &[u8; 4] = b"HYLL";
+const HEADER_LEN: usize = 16;
+const DENSE_LEN: usize = REGISTERS * 6 / 8;
+const DENSE: u8 = 0;
+const SPARSE: u8 = 1;
+/// Redis promotes a sparse HyperLogLog to dense once a register exceeds the largest value a `VAL` opcode can hold, or the encoding exceeds `hll-sparse-max-bytes`, which defaults to 3000.
+const SPARSE_MAX_VALUE: u8 = 32;
+const SPARSE_MAX_BYTES: usize = 3000;
+/// `rho` can't exceed `64 - P + 1`.
+const MAX_VALUE: u8 = 64 - P + 1;
+
+/// Hash `element` as Redis's `PFADD` does, arranged for the `HyperLogLog`.
+///
+/// Redis takes the register index from the low `P` bits of the hash and `rho` from the trailing zeros of the rest, whereas the `HyperLogLog` counts leading zeros, so those bits are reversed.
+pub fn hash(element: &[u8]) -> u64 {
+	let h = murmur64a(element, SEED);
+	let index = h & (REGISTERS as u64 - 1);
+	let w = (h >> P).reverse_bits() >> P;
+	w << P | index
+}
+
+/// Encode the registers `m` as a Redis string, sparse if it fits and dense otherwise.
+///
+/// The cached cardinality in the header is marked stale, so that Redis recomputes it on the next `PFCOUNT`.
+pub fn encode(m: &[u8]) -> Vec<u8> {
+	assert_eq!(m.len(), REGISTERS);
+	let mut ret = MAGIC.to_vec();
+	ret.extend_from_slice(&[DENSE, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x80]);
+	if m.iter().all(|&x| x <= SPARSE_MAX_VALUE) {
+		let sparse = encode_sparse(m);
+		if sparse.len() <= SPARSE_MAX_BYTES {
+			ret[4] = SPARSE;
+			ret.extend_from_slice(&sparse);
+			return ret;
+		}
+	}
+	ret.extend_from_slice(&Packed6::from_registers(m).as_bytes()[..DENSE_LEN]);
+	ret
+}
+
+fn encode_sparse(m: &[u8]) -> Vec<u8> {
+	let mut ret = Vec::new();
+	let mut i = 0;
+	while i < m.len() {
+		let x = m[i];
+		let mut run = m[i..].iter().take_while(|&&y| y == x).count();
+		i += run;
+		while run > 0 {
+			if x == 0 {
+				// ZERO: 00xxxxxx, XZERO: 01xxxxxx yyyyyyyy
+				let len = run.min(REGISTERS);
+				let [high, low] = u16::try_from(len - 1).unwrap().to_be_bytes();
+				if len <= 64 {
+					ret.push(low);
+				} else {
+					ret.extend_from_slice(&[0x40 | high, low]);
+				}
+				run -= len;
+			} else {
+				// VAL: 1vvvvvxx
+				let len = run.min(4);
+				ret.push(0x80 | (x - 1) << 2 | u8::try_from(len - 1).unwrap());
+				run -= len;
+			}
+		}
+	}
+	ret
+}
+
+/// Decode a Redis string in either encoding into one byte per register.
+pub fn decode(bytes: &[u8]) -> Result<Box<[u8]>, Error> {
+	if bytes.len() < HEADER_LEN || &bytes[..4] != MAGIC {
+		return Err(Error::Deserialization("not a Redis HyperLogLog"));
+	}
+	let (header, body) = bytes.split_at(HEADER_LEN);
+	let m = match header[4] {
+		DENSE => decode_dense(body)?,
+		SPARSE => decode_sparse(body)?,
+		_ => return Err(Error::Deserialization("unknown Redis HyperLogLog encoding")),
+	};
+	if m.iter().any(|&x| x > MAX_VALUE) {
+		return Err(Error::Deserialization(
+			"Redis HyperLogLog register out of range",
+		));
+	}
+	Ok(m)
+}
+
+fn decode_dense(body: &[u8]) -> Result<Box<[u8]>, Error> {
+	if body.len() != DENSE_LEN {
+		return Err(Error::Deserialization(
+			"Redis HyperLogLog dense registers have the wrong length",
+		));
+	}
+	Ok((0..REGISTERS)
+		.map(|i| {
+			let (byte, shift) = (i * 6 / 8, i * 6 % 8);
+			let word =
+				u16::from(body[byte]) | u16::from(body.get(byte + 1).copied().unwrap_or(0)) << 8;
+			u8::try_from(word >> shift & 0x3f).unwrap()
+		})
+		.collect())
+}
+
+fn decode_sparse(body: &[u8]) -> Result<Box<[u8]>, Error> {
+	let mut m = Vec::with_capacity(REGISTERS);
+	let mut bytes = body.iter();
+	while let Some(&op) = bytes.next() {
+		let (x, len) = match op >> 6 {
+			0b00 => (0, usize::from(op & 0x3f) + 1),
+			0b01 => {
+				let &low = bytes.next().ok_or(Error::Deserialization(
+					"Redis HyperLogLog sparse opcode truncated",
+				))?;
+				(0, usize::from(u16::from_be_bytes([op & 0x3f, low])) + 1)
+			}
+			_ => ((op >> 2 & 0x1f) + 1, usize::from(op & 0x3) + 1),
+		};
+		if m.len() + len > REGISTERS {
+			return Err(Error::Deserialization(
+				"Redis HyperLogLog sparse runs overflow the registers",
+			));
+		}
+		m.resize(m.len() + len, x);
+	}
+	if m.len() != REGISTERS {
+		return Err(Error::Deserialization(
+			"Redis HyperLogLog sparse runs don't cover the registers",
+		));
+	}
+	Ok(m.into_boxed_slice())
+}
@@ -0,0 +1,338 @@
+use serde::{Deserialize, Serialize};
+use std::{
+	convert::TryFrom, fmt, hash::{BuildHasher, Hash}, iter, marker::PhantomData
+};
+
+use super::{estimator, for_each_register, HyperLogLog, MAX_P, MIN_P};
+use crate::{error::Error, hash::XxHashBuilder, traits::UnionAssign};
+
+/// A sliding-window variant of [`HyperLogLog`], that estimates the cardinality of the elements pushed since any given timestamp.
+///
+/// Rather than its maximum `rho`, each register keeps a *list of future possible maxima*: the `(timestamp, rho)` pairs that would be the register's maximum for some window. A pair stops being a possible maximum once a pair with a later or equal timestamp and an equal or greater `rho` arrives, so each list is ordered by increasing timestamp and strictly decreasing `rho`, and holds at most `65 - p` pairs. [`len_since`](Self::len_since) reads each register as the first pair of its list that is inside the window.
+///
+/// See [*Sliding HyperLogLog: Estimating cardinality in a data stream over a sliding window*](https://hal.archives-ouvertes.fr/hal-00465313/document) by Yousra Chabchoub and Georges Hébrail.
+///
+/// Elements are hashed with `S`, which defaults to [`XxHashBuilder`] with a seed of `0`, and are assigned to registers as by [`HyperLogLog`]. Timestamps are in whatever unit the caller chooses, and needn't be pushed in order.
+#[derive(Serialize, Deserialize)]
+#[serde(
+	bound(serialize = "S: Serialize", deserialize = "S: Deserialize<'de>"),
+	try_from = "SlidingHyperLogLogUnchecked<V, S>"
+)]
+pub struct SlidingHyperLogLog<V: ?Sized, S = XxHashBuilder> {
+	p: u8,
+	m: Box<[Vec<(u64, u8)>]>,
+	hasher: S,
+	marker: PhantomData<fn(V)>,
+}
+
+/// A deserialized [`SlidingHyperLogLog`] that hasn't yet been checked to be valid.
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "S: Deserialize<'de>"))]
+struct SlidingHyperLogLogUnchecked<V: ?Sized, S> {
+	p: u8,
+	m: Box<[Vec<(u64, u8)>]>,
+	hasher: S,
+	marker: PhantomData<fn(V)>,
+}
+impl<V: ?Sized, S> TryFrom<SlidingHyperLogLogUnchecked<V, S>> for SlidingHyperLogLog<V, S> {
+	type Error = Error;
+
+	fn try_from(hll: SlidingHyperLogLogUnchecked<V, S>) -> Result<Self, Error> {
+		let SlidingHyperLogLogUnchecked { p, m, hasher, .. } = hll;
+		if !(MIN_P..=MAX_P).contains(&p) {
+			return Err(Error::Deserialization(
+				"SlidingHyperLogLog precision out of range",
+			));
+		}
+		if m.len() != 1 << p
+			|| m.iter().any(|list| {
+				list.iter().any(|&(_, rho)| rho == 0 || rho > 65 - p)
+					|| list
+						.windows(2)
+						.any(|w| w[0].0 >= w[1].0 || w[0].1 <= w[1].1)
+			}) {
+			return Err(Error::Deserialization(
+				"SlidingHyperLogLog registers are malformed",
+			));
+		}
+		Ok(Self {
+			p,
+			m,
+			hasher,
+			marker: PhantomData,
+		})
+	}
+}
+
+impl<V: ?Sized> SlidingHyperLogLog<V>
+where
+	V: Hash,
+{
+	/// Create an empty `SlidingHyperLogLog` data structure with the specified error tolerance.
+	pub fn new(error_rate: f64) -> Self {
+		Self::with_hasher(error_rate, XxHashBuilder::default())
+	}
+
+	/// Like [`new`](Self::new), but returns an error if the error tolerance is invalid.
+	pub fn try_new(error_rate: f64) -> Result<Self, Error> {
+		Self::try_with_hasher(error_rate, XxHashBuilder::default())
+	}
+
+	/// Create an empty `SlidingHyperLogLog` data structure with 2<sup>`p`</sup> registers.
+	pub fn with_precision(p: u8) -> Self {
+		Self::with_precision_and_hasher(p, XxHashBuilder::default())
+	}
+
+	/// Like [`with_precision`](Self::with_precision), but returns an error if `p` is out of range.
+	pub fn try_with_precision(p: u8) -> Result<Self, Error> {
+		Self::try_with_precision_and_hasher(p, XxHashBuilder::default())
+	}
+}
+impl<V: ?Sized, S> SlidingHyperLogLog<V, S>
+where
+	V: Hash,
+	S: BuildHasher,
+{
+	/// Create an empty `SlidingHyperLogLog` data structure with the specified error tolerance, that hashes elements with `hasher`.
+	///
+	/// # Panics
+	///
+	/// Panics if the error tolerance is invalid, as for [`try_with_hasher`](Self::try_with_hasher).
+	pub fn with_hasher(error_rate: f64, hasher: S) -> Self {
+		Self::try_with_hasher(error_rate, hasher).unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`with_hasher`](Self::with_hasher), but returns an error if the error tolerance is invalid.
+	pub fn try_with_hasher(error_rate: f64, hasher: S) -> Result<Self, Error> {
+		let p = HyperLogLog::<V, S>::get_p(error_rate)?;
+		Self::try_with_precision_and_hasher(p, hasher)
+	}
+
+	/// Create an empty `SlidingHyperLogLog` data structure with 2<sup>`p`</sup> registers, that hashes elements with `hasher`.
+	///
+	/// # Panics
+	///
+	/// Panics if `p` is out of range, as for [`try_with_precision_and_hasher`](Self::try_with_precision_and_hasher).
+	pub fn with_precision_and_hasher(p: u8, hasher: S) -> Self {
+		Self::try_with_precision_and_hasher(p, hasher).unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`with_precision_and_hasher`](Self::with_precision_and_hasher), but returns an error if `p` is out of range.
+	pub fn try_with_precision_and_hasher(p: u8, hasher: S) -> Result<Self, Error> {
+		HyperLogLog::<V, S>::check_p(p)?;
+		Ok(Self {
+			p,
+			m: iter::repeat_with(Vec::new).take(1 << p).collect(),
+			hasher,
+			marker: PhantomData,
+		})
+	}
+
+	/// "Visit" an element at time `ts`.
+	pub fn push_at(&mut self, value: &V, ts: u64) {
+		let x = self.hasher.hash_one(value);
+		let m = &mut self.m;
+		for_each_register(&[x], self.p, |index, rho| insert(&mut m[index], ts, rho));
+	}
+
+	/// Retrieve an estimate of the cardinality of the elements pushed at or after time `ts`.
+	pub fn len_since(&self, ts: u64) -> f64 {
+		let mut c = vec![0; usize::from(66 - self.p)];
+		for list in &*self.m {
+			let x = list
+				.get(list.partition_point(|&(t, _)| t < ts))
+				.map_or(0, |&(_, rho)| rho);
+			c[usize::from(x)] += 1;
+		}
+		estimator::improved(&c)
+	}
+
+	/// Retrieve an estimate of the cardinality of all the elements pushed.
+	pub fn len(&self) -> f64 {
+		self.len_since(0)
+	}
+
+	/// Returns true if empty.
+	pub fn is_empty(&self) -> bool {
+		self.m.iter().all(Vec::is_empty)
+	}
+
+	/// The precision `p` of this `SlidingHyperLogLog`, i.e. it has 2<sup>p</sup> registers.
+	pub fn precision(&self) -> u8 {
+		self.p
+	}
+
+	/// Forget the elements pushed before time `ts`, which [`len_since`](Self::len_since) will no longer count for earlier timestamps.
+	pub fn expire(&mut self, ts: u64) {
+		for list in &mut *self.m {
+			let _ = list.drain(..list.partition_point(|&(t, _)| t < ts));
+		}
+	}
+
+	/// Merge another `SlidingHyperLogLog` into this one, so that every window counts the elements pushed to either.
+	///
+	/// # Panics
+	///
+	/// Panics if the precisions or hashers differ, as for [`try_union`](Self::try_union).
+	pub fn union(&mut self, src: &Self)
+	where
+		S: PartialEq,
+	{
+		self.try_union(src).unwrap_or_else(|err| panic!("{}", err));
+	}
+
+	/// Like [`union`](Self::union), but returns an error if the precisions or hashers differ.
+	pub fn try_union(&mut self, src: &Self) -> Result<(), Error>
+	where
+		S: PartialEq,
+	{
+		if self.p != src.p {
+			return Err(Error::Incompatible(
+				"SlidingHyperLogLogs must have the same precision to be unioned",
+			));
+		}
+		if self.hasher != src.hasher {
+			return Err(Error::Incompatible(
+				"SlidingHyperLogLogs must have the same hasher to be unioned",
+			));
+		}
+		for (list, src) in self.m.iter_mut().zip(src.m.iter()) {
+			for &(ts, rho) in src {
+				insert(list, ts, rho);
+			}
+		}
+		Ok(())
+	}
+
+	/// Clears the `SlidingHyperLogLog` data structure, as if it was new.
+	pub fn clear(&mut self) {
+		self.m.iter_mut().for_each(Vec::clear);
+	}
+}
+
+/// Add `(ts, rho)` to the list of future possible maxima `list`, unless it's dominated by a pair already there, and remove the pairs that it dominates.
+fn insert(list: &mut Vec<(u64, u8)>, ts: u64, rho: u8) {
+	// the first pair at or after ts has the greatest rho of them
+	let i = list.partition_point(|&(t, _)| t < ts);
+	if matches!(list.get(i), Some(&(_, r)) if r >= rho) {
+		return;
+	}
+	// the pairs before ts with no greater rho are at the end of list[..i], plus a pair at ts with a lesser rho
+	let start = list[..i].partition_point(|&(_, r)| r > rho);
+	let end = i + usize::from(matches!(list.get(i), Some(&(t, _)) if t == ts));
+	let _ = list.splice(start..end, iter::once((ts, rho)));
+}
+
+impl<V: ?Sized, S: Clone> Clone for SlidingHyperLogLog<V, S> {
+	fn clone(&self) -> Self {
+		Self {
+			p: self.p,
+			m: self.m.clone(),
+			hasher: self.hasher.clone(),
+			marker: PhantomData,
+		}
+	}
+}
+impl<V: ?Sized, S> fmt::Debug for SlidingHyperLogLog<V, S>
+where
+	V: Hash,
+	S: BuildHasher,
+{
+	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+		fmt.debug_struct("SlidingHyperLogLog")
+			.field("len", &self.len())
+			.finish()
+	}
+}
+impl<'a, V: ?Sized, S> UnionAssign<&'a SlidingHyperLogLog<V, S>> for SlidingHyperLogLog<V, S>
+where
+	V: Hash,
+	S: BuildHasher + PartialEq,
+{
+	fn union_assign(&mut self, rhs: &'a Self) {
+		self.union(rhs);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::{insert, SlidingHyperLogLog};
+	use crate::{
+		error::Error, hash::XxHashBuilder, traits::UnionAssign, u64_to_f64, Estimator, HyperLogLog
+	};
+
+	#[test]
+	fn future_possible_maxima() {
+		let mut list = Vec::new();
+		for &(ts, rho) in &[
+			(1, 3),
+			(2, 5),
+			(3, 2),
+			(3, 1),
+			(4, 2),
+			(5, 4),
+			(5, 6),
+			(6, 1),
+		] {
+			insert(&mut list, ts, rho);
+		}
+		assert_eq!(list, vec![(5, 6), (6, 1)]);
+		insert(&mut list, 0, 7);
+		insert(&mut list, 2, 6);
+		insert(&mut list, 5, 3);
+		assert_eq!(list, vec![(0, 7), (5, 6), (6, 1)]);
+	}
+
+	#[test]
+	fn windows() {
+		let mut sliding = SlidingHyperLogLog::with_precision(12);
+		for i in 0..100_000_u64 {
+			sliding.push_at(&(i % 30_000), i / 1000);
+		}
+		for ts in (0..=100).step_by(10) {
+			// the registers are those of a HyperLogLog of just the elements since ts
+			let mut hll = HyperLogLog::new_with_counters(0.02);
+			hll.set_estimator(Estimator::Improved);
+			for i in ts * 1000..100_000 {
+				hll.push(&(i % 30_000));
+			}
+			assert_eq!(sliding.len_since(ts), hll.len());
+			let actual = u64_to_f64(((100 - ts) * 1000).min(30_000));
+			assert!((sliding.len_since(ts) - actual).abs() <= actual * 0.05);
+		}
+		assert_eq!(sliding.len(), sliding.len_since(0));
+	}
+
+	#[test]
+	fn union() {
+		let mut a = SlidingHyperLogLog::with_precision(10);
+		let mut b = SlidingHyperLogLog::with_precision(10);
+		let mut both = SlidingHyperLogLog::with_precision(10);
+		for i in 0..20_000_u64 {
+			let ts = (i * 7919) % 1000;
+			if i % 3 == 0 {
+				a.push_at(&i, ts);
+			} else {
+				b.push_at(&i, ts);
+			}
+			both.push_at(&i, ts);
+		}
+		a.union_assign(&b);
+		for ts in (0..1000).step_by(100) {
+			assert_eq!(a.len_since(ts), both.len_since(ts));
+		}
+		a.expire(500);
+		assert_eq!(a.len_since(500), both.len_since(500));
+		assert!(a.len_since(0) < both.len_since(0));
+		assert!(matches!(
+			a.try_union(&SlidingHyperLogLog::with_precision(11)),
+			Err(Error::Incompatible(_))
+		));
+		let sliding = SlidingHyperLogLog::<u64, _>::with_precision_and_hasher(
+			10,
+			XxHashBuilder::with_seed(1),
+		);
+		assert!(sliding.is_empty());
+		assert!(matches!(a.try_union(&sliding), Err(Error::Incompatible(_))));
+	}
+}
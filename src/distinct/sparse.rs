@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use std::{
+	cmp::{self, Ordering}, convert::TryFrom
+};
+
+use super::{fold_rho, get_rho};
+use crate::u64_to_f64;
+
+/// The precision of the sparse representation, *p'* in the HyperLogLog++ paper.
+pub const P_SPARSE: u8 = 26;
+
+/// The sparse representation of the registers of a [`HyperLogLog`](super::HyperLogLog).
+///
+/// A list of the non-zero registers at precision [`P_SPARSE`], sorted by index, each encoded as `index << 6 | rho`.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Sparse(Vec<u32>);
+impl Sparse {
+	pub fn new() -> Self {
+		Self(Vec::new())
+	}
+
+	/// The number of entries above which the dense registers at precision `p` are smaller.
+	///
+	/// This is capped as each insertion into the sorted list is linear in its length, which would make filling the sparse representation of a high precision `HyperLogLog` quadratic.
+	pub fn max_len(p: u8) -> usize {
+		cmp::min((1 << p) / 4, 1 << 14)
+	}
+
+	pub fn len(&self) -> usize {
+		self.0.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+
+	/// The non-zero registers as `(index, rho)`, sorted by index.
+	pub fn iter(&self) -> impl Iterator<Item = (u32, u8)> + '_ {
+		self.0
+			.iter()
+			.map(|&entry| (entry >> 6, u8::try_from(entry & 0x3f).unwrap()))
+	}
+
+	/// Whether the entries are sorted by index, and the indices and rhos are in range.
+	pub fn is_valid(&self) -> bool {
+		self.0.iter().all(|&entry| {
+			entry >> 6 < 1 << P_SPARSE && (1..=65 - u32::from(P_SPARSE)).contains(&(entry & 0x3f))
+		}) && self.0.windows(2).all(|w| w[0] >> 6 < w[1] >> 6)
+	}
+
+	/// "Visit" the hash `x` of an element.
+	pub fn push(&mut self, x: u64) {
+		let index = u32::try_from(x & ((1 << P_SPARSE) - 1)).unwrap();
+		let rho = get_rho(x >> P_SPARSE, 64 - P_SPARSE);
+		let entry = index << 6 | u32::from(rho);
+		match self.0.binary_search_by_key(&index, |entry| entry >> 6) {
+			Ok(i) => {
+				// entries with the same index are ordered by rho
+				if self.0[i] < entry {
+					self.0[i] = entry;
+				}
+			}
+			Err(i) => self.0.insert(i, entry),
+		}
+	}
+
+	/// Merge another sparse representation into `self`.
+	pub fn union(&mut self, src: &Self) {
+		let mut ret = Vec::with_capacity(self.0.len() + src.0.len());
+		let (mut a, mut b) = (
+			self.0.iter().copied().peekable(),
+			src.0.iter().copied().peekable(),
+		);
+		loop {
+			let next = match (a.peek(), b.peek()) {
+				(Some(&x), Some(&y)) => match (x >> 6).cmp(&(y >> 6)) {
+					Ordering::Less => a.next(),
+					Ordering::Greater => b.next(),
+					// entries with the same index are ordered by rho
+					Ordering::Equal => a.next().max(b.next()),
+				},
+				(Some(_), None) => a.next(),
+				(None, Some(_)) => b.next(),
+				(None, None) => break,
+			};
+			ret.push(next.unwrap());
+		}
+		self.0 = ret;
+	}
+
+	/// Estimate the cardinality with linear counting, which is accurate given the large number of registers.
+	pub fn len_estimate(&self) -> f64 {
+		let m = u64_to_f64(1 << P_SPARSE);
+		m * (m / (m - u64_to_f64(self.0.len() as u64))).ln()
+	}
+
+	/// The number of registers with each value `0..=65-P_SPARSE`.
+	pub fn histogram(&self) -> Vec<usize> {
+		let mut c = vec![0; usize::from(66 - P_SPARSE)];
+		c[0] = (1 << P_SPARSE) - self.0.len();
+		for &entry in &self.0 {
+			c[usize::try_from(entry & 0x3f).unwrap()] += 1;
+		}
+		c
+	}
+
+	/// Merge the registers into the dense registers `m` of precision `p`.
+	pub fn fold_into(&self, m: &mut [u8], p: u8) {
+		assert_eq!(m.len(), 1 << p);
+		for &entry in &self.0 {
+			let index = u64::from(entry >> 6);
+			let rho = fold_rho(index, u8::try_from(entry & 0x3f).unwrap(), P_SPARSE, p);
+			let register = &mut m[usize::try_from(index).unwrap() & ((1 << p) - 1)];
+			*register = (*register).max(rho);
+		}
+	}
+
+	/// Convert to the dense registers of precision `p`.
+	pub fn to_dense(&self, p: u8) -> Box<[u8]> {
+		let mut m = vec![0; 1 << p].into_boxed_slice();
+		self.fold_into(&mut m, p);
+		m
+	}
+}
@@ -0,0 +1,22 @@
+use std::{error, fmt};
+
+/// The error type for the fallible operations in this crate.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Error {
+	/// A parameter was outside of its valid range.
+	InvalidParameter(&'static str),
+	/// Two data structures could not be combined as they were created with different parameters.
+	Incompatible(&'static str),
+	/// A serialized data structure was malformed.
+	Deserialization(&'static str),
+}
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::InvalidParameter(msg) => write!(f, "invalid parameter: {msg}"),
+			Error::Incompatible(msg) => write!(f, "incompatible data structures: {msg}"),
+			Error::Deserialization(msg) => write!(f, "malformed serialized data: {msg}"),
+		}
+	}
+}
+impl error::Error for Error {}
@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use std::{
+	convert::{TryFrom, TryInto}, hash::BuildHasher
+};
+use twox_hash::XxHash;
+
+/// A [`BuildHasher`] that creates [`XxHash`] hashers with a specified seed.
+///
+/// This is the default hash function used by [`HyperLogLog`](crate::HyperLogLog), [`CountMinSketch`](crate::CountMinSketch) and [`Top`](crate::Top). Unlike [`RandomXxHashBuilder`](twox_hash::RandomXxHashBuilder) the seed is serialized along with the data structure, so that deserialized instances hash identically to the originals. The default seed is `0`.
+///
+/// Data structures can only be merged with others that were created with the same seed.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default, Debug, Serialize, Deserialize)]
+pub struct XxHashBuilder {
+	seed: u64,
+}
+impl XxHashBuilder {
+	/// Create an `XxHashBuilder` with the specified seed.
+	pub fn with_seed(seed: u64) -> Self {
+		Self { seed }
+	}
+
+	/// Create an `XxHashBuilder` with a random seed, so that colliding keys cannot be crafted by someone who knows the seed.
+	pub fn random() -> Self {
+		Self::with_seed(rand::random())
+	}
+
+	/// The seed used to create hashers.
+	pub fn seed(&self) -> u64 {
+		self.seed
+	}
+}
+impl BuildHasher for XxHashBuilder {
+	type Hasher = XxHash;
+
+	fn build_hasher(&self) -> XxHash {
+		XxHash::with_seed(self.seed)
+	}
+}
+
+/// [MurmurHash64A](https://github.com/aappleby/smhasher/blob/master/src/MurmurHash2.cpp), reading the input as little-endian words as Redis does.
+pub(crate) fn murmur64a(data: &[u8], seed: u64) -> u64 {
+	const M: u64 = 0xc6a4_a793_5bd1_e995;
+	const R: u32 = 47;
+	let mut h = seed ^ u64::try_from(data.len()).unwrap().wrapping_mul(M);
+	let mut chunks = data.chunks_exact(8);
+	for chunk in &mut chunks {
+		let mut k = u64::from_le_bytes(chunk.try_into().unwrap());
+		k = k.wrapping_mul(M);
+		k ^= k >> R;
+		k = k.wrapping_mul(M);
+		h ^= k;
+		h = h.wrapping_mul(M);
+	}
+	let tail = chunks.remainder();
+	if !tail.is_empty() {
+		for (i, &byte) in tail.iter().enumerate() {
+			h ^= u64::from(byte) << (8 * i);
+		}
+		h = h.wrapping_mul(M);
+	}
+	h ^= h >> R;
+	h = h.wrapping_mul(M);
+	h ^= h >> R;
+	h
+}
+
+/// The 128-bit x64 variant of [MurmurHash3](https://github.com/aappleby/smhasher/blob/master/src/MurmurHash3.cpp), returning the two halves `(h1, h2)`. The seed initializes both halves, as in Apache DataSketches.
+pub(crate) fn murmur3_x64_128(data: &[u8], seed: u64) -> (u64, u64) {
+	const C1: u64 = 0x87c3_7b91_1142_53d5;
+	const C2: u64 = 0x4cf5_ad43_2745_937f;
+	let mix_k1 = |k: u64| k.wrapping_mul(C1).rotate_left(31).wrapping_mul(C2);
+	let mix_k2 = |k: u64| k.wrapping_mul(C2).rotate_left(33).wrapping_mul(C1);
+	let (mut h1, mut h2) = (seed, seed);
+	let mut chunks = data.chunks_exact(16);
+	for chunk in &mut chunks {
+		let k1 = u64::from_le_bytes(chunk[..8].try_into().unwrap());
+		let k2 = u64::from_le_bytes(chunk[8..].try_into().unwrap());
+		h1 ^= mix_k1(k1);
+		h1 = h1
+			.rotate_left(27)
+			.wrapping_add(h2)
+			.wrapping_mul(5)
+			.wrapping_add(0x52dc_e729);
+		h2 ^= mix_k2(k2);
+		h2 = h2
+			.rotate_left(31)
+			.wrapping_add(h1)
+			.wrapping_mul(5)
+			.wrapping_add(0x3849_5ab5);
+	}
+	let tail = chunks.remainder();
+	let mut k = [0_u64; 2];
+	for (i, &byte) in tail.iter().enumerate() {
+		k[i / 8] |= u64::from(byte) << (8 * (i % 8));
+	}
+	if tail.len() > 8 {
+		h2 ^= mix_k2(k[1]);
+	}
+	if !tail.is_empty() {
+		h1 ^= mix_k1(k[0]);
+	}
+	let len = u64::try_from(data.len()).unwrap();
+	h1 ^= len;
+	h2 ^= len;
+	h1 = h1.wrapping_add(h2);
+	h2 = h2.wrapping_add(h1);
+	h1 = fmix64(h1);
+	h2 = fmix64(h2);
+	h1 = h1.wrapping_add(h2);
+	h2 = h2.wrapping_add(h1);
+	(h1, h2)
+}
+
+/// The MurmurHash3 finalizer, a bijection that mixes every bit of `k` into every bit of the result.
+pub(crate) fn fmix64(mut k: u64) -> u64 {
+	k ^= k >> 33;
+	k = k.wrapping_mul(0xff51_afd7_ed55_8ccd);
+	k ^= k >> 33;
+	k = k.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+	k ^= k >> 33;
+	k
+}
+
+#[cfg(test)]
+mod test {
+	use super::murmur3_x64_128;
+
+	#[test]
+	fn murmur3() {
+		assert_eq!(
+			murmur3_x64_128(b"The quick brown fox jumps over the lazy dog", 0),
+			(0xe34b_bc7b_bc07_1b6c, 0x7a43_3ca9_c49a_9347)
+		);
+		assert_eq!(murmur3_x64_128(b"", 0), (0, 0));
+	}
+}
@@ -7,17 +7,15 @@
 //! This library is a work in progress. PRs are very welcome! Currently implemented algorithms include:
 //!
 //!  * Count–min sketch
+//!  * Count sketch
 //!  * Top k (Count–min sketch plus a doubly linked hashmap to track heavy hitters / top k keys when ordered by aggregated value)
 //!  * HyperLogLog
+//!  * Sliding-window HyperLogLog
 //!  * Reservoir sampling
 //!
 //! A goal of this library is to enable composition of these algorithms; for example Top k + HyperLogLog to enable an approximate version of something akin to `SELECT key FROM table GROUP BY key ORDER BY COUNT(DISTINCT value) DESC LIMIT k`.
 //!
-//! Run your application with `RUSTFLAGS="-C target-cpu=native"` and the `nightly` feature to benefit from the SIMD-acceleration like so:
-//!
-//! ```bash
-//! RUSTFLAGS="-C target-cpu=native" cargo run --features "streaming_algorithms/nightly" --release
-//! ```
+//! The SIMD-accelerated paths are selected at runtime according to the features of the CPU, so they work on stable Rust and a single binary can be deployed to heterogeneous hosts.
 //!
 //! See [this gist](https://gist.github.com/debasishg/8172796) for a good list of further algorithms to be implemented. Other resources are [Probabilistic data structures – Wikipedia](https://en.wikipedia.org/wiki/Category:Probabilistic_data_structures), [DataSketches – A similar Java library originating at Yahoo](https://datasketches.github.io/), and [Algebird  – A similar Java library originating at Twitter](https://github.com/twitter/algebird).
 //!
@@ -47,19 +45,28 @@
 	clippy::float_cmp,
 	clippy::unsafe_derive_deserialize,
 	clippy::must_use_candidate,
-	clippy::unused_self
+	clippy::unused_self,
+	clippy::missing_errors_doc
 )]
 
+mod bounds;
 mod count_min;
+mod count_sketch;
 mod distinct;
+mod error;
+mod hash;
 mod linked_list;
 mod ordered_linked_list;
 mod sample;
 mod top;
 mod traits;
 
+pub use bounds::*;
 pub use count_min::*;
+pub use count_sketch::*;
 pub use distinct::*;
+pub use error::*;
+pub use hash::*;
 pub use sample::*;
 pub use top::*;
 pub use traits::*;
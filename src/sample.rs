@@ -2,6 +2,8 @@ use rand::{self, Rng, SeedableRng};
 use serde::{de::Deserializer, ser::Serializer, Deserialize, Serialize};
 use std::{convert::TryFrom, fmt, iter, ops, vec};
 
+use crate::error::Error;
+
 /// Given population and sample sizes, returns true if this element is in the sample. Without replacement.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SampleTotal {
@@ -13,13 +15,22 @@ pub struct SampleTotal {
 impl SampleTotal {
 	/// Create a `SampleTotal` that will provide a sample of size `samples` of a population of size `total`.
 	pub fn new(total: usize, samples: usize) -> Self {
-		assert!(total >= samples);
-		Self {
+		Self::try_new(total, samples).unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`new`](Self::new), but returns an error if `samples` is greater than `total`.
+	pub fn try_new(total: usize, samples: usize) -> Result<Self, Error> {
+		if samples > total {
+			return Err(Error::InvalidParameter(
+				"samples must not be greater than total",
+			));
+		}
+		Ok(Self {
 			total,
 			samples,
 			picked: 0,
 			i: 0,
-		}
+		})
 	}
 
 	/// Returns whether or not to this value is in the sample
@@ -34,7 +45,13 @@ impl SampleTotal {
 }
 impl Drop for SampleTotal {
 	fn drop(&mut self) {
-		assert_eq!(self.picked, self.samples);
+		// Dropping before the whole population has been seen is a logic error, but not one worth aborting a release build over.
+		debug_assert!(
+			self.picked == self.samples || std::thread::panicking(),
+			"SampleTotal dropped after picking {} of {} samples",
+			self.picked,
+			self.samples
+		);
 	}
 }
 
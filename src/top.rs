@@ -1,11 +1,11 @@
 use serde::{Deserialize, Serialize};
 use std::{
-	cmp, collections::{hash_map::Entry, HashMap}, fmt::{self, Debug}, hash::Hash, iter, ops
+	cmp, collections::{hash_map::Entry, HashMap}, fmt::{self, Debug}, hash::{BuildHasher, Hash}, iter, ops
 };
 use twox_hash::RandomXxHashBuilder;
 
 use crate::{
-	count_min::CountMinSketch, ordered_linked_list::{OrderedLinkedList, OrderedLinkedListIndex, OrderedLinkedListIter}, traits::{Intersect, New, UnionAssign}, IntersectPlusUnionIsPlus
+	count_min::CountMinSketch, error::Error, hash::XxHashBuilder, ordered_linked_list::{OrderedLinkedList, OrderedLinkedListIndex, OrderedLinkedListIter}, traits::{Intersect, New, UnionAssign}, IntersectPlusUnionIsPlus
 };
 
 /// This probabilistic data structure tracks the `n` top keys given a stream of `(key,value)` tuples, ordered by the sum of the values for each key (the "aggregated value"). It uses only `O(n)` space.
@@ -32,26 +32,58 @@ use crate::{
 /// ```
 ///
 /// See [*An Improved Data Stream Summary: The Count-Min Sketch and its Applications*](http://dimacs.rutgers.edu/~graham/pubs/papers/cm-full.pdf) and [*New Directions in Traffic Measurement and Accounting*](http://pages.cs.wisc.edu/~suman/courses/740/papers/estan03tocs.pdf) for background on the count-min sketch with conservative updating.
+///
+/// Keys are hashed by the count-min sketch with `S`, which defaults to [`XxHashBuilder`] with a seed of `0`. The hashmap has its own randomly seeded hasher, so that keys colliding in it can't be crafted.
 #[derive(Clone, Serialize, Deserialize)]
 #[serde(bound(
-	serialize = "A: Hash + Eq + Serialize, C: Serialize, <C as New>::Config: Serialize",
-	deserialize = "A: Hash + Eq + Deserialize<'de>, C: Deserialize<'de>, <C as New>::Config: Deserialize<'de>"
+	serialize = "A: Hash + Eq + Serialize, C: Serialize, <C as New>::Config: Serialize, S: Serialize",
+	deserialize = "A: Hash + Eq + Deserialize<'de>, C: Deserialize<'de>, <C as New>::Config: Deserialize<'de>, S: Deserialize<'de>"
 ))]
-pub struct Top<A, C: New> {
+pub struct Top<A, C: New, S = XxHashBuilder> {
 	map: HashMap<A, OrderedLinkedListIndex<'static>, RandomXxHashBuilder>,
 	list: OrderedLinkedList<Node<A, C>>,
-	count_min: CountMinSketch<A, C>,
+	count_min: CountMinSketch<A, C, S>,
 	config: <C as New>::Config,
 }
 impl<A: Hash + Eq + Clone, C: Ord + New + for<'a> UnionAssign<&'a C> + Intersect> Top<A, C> {
 	/// Create an empty `Top` data structure with the specified `n` capacity.
 	pub fn new(n: usize, probability: f64, tolerance: f64, config: <C as New>::Config) -> Self {
-		Self {
+		Self::with_hasher(n, probability, tolerance, config, XxHashBuilder::default())
+	}
+
+	/// Like [`new`](Self::new), but returns an error if `probability` or `tolerance` are invalid.
+	pub fn try_new(
+		n: usize, probability: f64, tolerance: f64, config: <C as New>::Config,
+	) -> Result<Self, Error> {
+		Self::try_with_hasher(n, probability, tolerance, config, XxHashBuilder::default())
+	}
+}
+impl<
+		A: Hash + Eq + Clone,
+		C: Ord + New + for<'a> UnionAssign<&'a C> + Intersect,
+		S: BuildHasher + Clone,
+	> Top<A, C, S>
+{
+	/// Create an empty `Top` data structure with the specified `n` capacity, that hashes keys with `hasher`.
+	pub fn with_hasher(
+		n: usize, probability: f64, tolerance: f64, config: <C as New>::Config, hasher: S,
+	) -> Self {
+		Self::try_with_hasher(n, probability, tolerance, config, hasher)
+			.unwrap_or_else(|err| panic!("{}", err))
+	}
+
+	/// Like [`with_hasher`](Self::with_hasher), but returns an error if `probability` or `tolerance` are invalid.
+	pub fn try_with_hasher(
+		n: usize, probability: f64, tolerance: f64, config: <C as New>::Config, hasher: S,
+	) -> Result<Self, Error> {
+		let count_min =
+			CountMinSketch::try_with_hasher(probability, tolerance, config.clone(), hasher)?;
+		Ok(Self {
 			map: HashMap::with_capacity_and_hasher(n, RandomXxHashBuilder::default()),
 			list: OrderedLinkedList::new(n),
-			count_min: CountMinSketch::new(probability, tolerance, config.clone()),
+			count_min,
 			config,
-		}
+		})
 	}
 	fn assert(&self) {
 		if !cfg!(feature = "assert") {
@@ -121,7 +153,8 @@ impl<A: Hash + Eq + Clone, C: Ord + New + for<'a> UnionAssign<&'a C> + Intersect
 impl<
 		A: Hash + Eq + Clone + Debug,
 		C: Ord + New + Clone + for<'a> UnionAssign<&'a C> + Intersect + Debug,
-	> Debug for Top<A, C>
+		S: BuildHasher + Clone,
+	> Debug for Top<A, C, S>
 {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		f.debug_list().entries(self.iter()).finish()
@@ -162,11 +195,12 @@ impl<
 			+ for<'a> UnionAssign<&'a C>
 			+ Intersect
 			+ IntersectPlusUnionIsPlus,
-	> iter::Sum<Top<A, C>> for Option<Top<A, C>>
+		S: BuildHasher + Clone,
+	> iter::Sum<Top<A, C, S>> for Option<Top<A, C, S>>
 {
 	fn sum<I>(mut iter: I) -> Self
 	where
-		I: Iterator<Item = Top<A, C>>,
+		I: Iterator<Item = Top<A, C, S>>,
 	{
 		let mut total = iter.next()?;
 		for sample in iter {
@@ -184,7 +218,8 @@ impl<
 			+ for<'a> UnionAssign<&'a C>
 			+ Intersect
 			+ IntersectPlusUnionIsPlus,
-	> ops::Add for Top<A, C>
+		S: BuildHasher + Clone,
+	> ops::Add for Top<A, C, S>
 {
 	type Output = Self;
 	fn add(mut self, other: Self) -> Self {
@@ -201,7 +236,8 @@ impl<
 			+ for<'a> UnionAssign<&'a C>
 			+ Intersect
 			+ IntersectPlusUnionIsPlus,
-	> ops::AddAssign for Top<A, C>
+		S: BuildHasher + Clone,
+	> ops::AddAssign for Top<A, C, S>
 {
 	fn add_assign(&mut self, other: Self) {
 		assert_eq!(self.capacity(), other.capacity());
@@ -364,6 +400,28 @@ mod test {
 		// println!("{:#?}", x);
 	}
 
+	#[test]
+	fn serde() {
+		let hasher = XxHashBuilder::with_seed(7);
+		let mut top = Top::<u64, u64, _>::with_hasher(10, 0.99, 2.0 / 100.0, (), hasher);
+		for i in 0..1000_u64 {
+			top.push(i * i % 97, &1);
+		}
+		let json = serde_json::to_string(&top).unwrap();
+		let mut copy: Top<u64, u64, XxHashBuilder> = serde_json::from_str(&json).unwrap();
+		for i in 1000..2000_u64 {
+			top.push(i * i % 97, &1);
+			copy.push(i * i % 97, &1);
+		}
+		assert_eq!(
+			copy.iter().collect::<Vec<_>>(),
+			top.iter().collect::<Vec<_>>()
+		);
+		for key in 0..97 {
+			assert_eq!(copy.count_min.get(&key), top.count_min.get(&key));
+		}
+	}
+
 	#[ignore] // takes too long on CI
 	#[test]
 	fn many() {
@@ -135,3 +135,22 @@ macro_rules! impl_ipuip {
 }
 
 impl_ipuip!(u8 i8 u16 i16 u32 i32 u64 i64 u128 i128 usize isize);
+
+/// Convert `&Self` to an `f64`, so that error bounds can be calculated for it.
+pub trait ToF64 {
+	/// Convert, rounding to the nearest `f64` if necessary.
+	fn to_f64(&self) -> f64;
+}
+
+macro_rules! impl_to_f64 {
+	($($t:ty)*) => ($(
+		impl ToF64 for $t {
+			#[allow(clippy::cast_precision_loss, clippy::cast_lossless)]
+			fn to_f64(&self) -> f64 {
+				*self as f64
+			}
+		}
+	)*)
+}
+
+impl_to_f64!(u8 i8 u16 i16 u32 i32 u64 i64 u128 i128 usize isize);